@@ -28,11 +28,8 @@ impl Visitor {
         let path_without_rs = path_from_src.strip_suffix(".rs").unwrap();
         let rust_path = &format!("crate::{}", path_without_rs.replace("/", "::"));
         for item in syntax.items {
-            match item {    
-                syn::Item::Struct(s) => {
-                    self.visit_struct(rust_path, s);
-                },
-                _ => {}
+            if let syn::Item::Struct(s) = item {
+                self.visit_struct(rust_path, s);
             }
         }
     }
@@ -65,24 +62,24 @@ fn main() {
     let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
     let src = format!("{}/src", dir);
     let mut visitor = Visitor::new();
-    for entry in WalkDir::new(src) {
+    for entry in WalkDir::new(src).sort_by_file_name() {
         let entry = entry.unwrap();
         if entry.path().extension() == Some(std::ffi::OsStr::new("rs")) {
             let relative_path = entry.path().strip_prefix(&dir).unwrap();
             visitor.visit(relative_path.to_str().unwrap());
         }
     }
-    let enum_variants = (&visitor.macros).iter()
+    let enum_variants = visitor.macros.iter()
         .map(|m| {
             let info = &m.info;
             let enum_name = &m.enum_name;
             TokenStream::from_str(&format!("{}({})", enum_name, info)).unwrap()
         }).collect::<Vec<_>>();
-    let match_arms = (&visitor.macros).iter()
+    let match_arms = visitor.macros.iter()
         .map(|m| {
             let runner = &m.runner;
             let enum_name = &m.enum_name;
-            TokenStream::from_str(&format!("WorkflowStage::{}(d) => Box::new({}::new(&d))", enum_name, runner)).unwrap()
+            TokenStream::from_str(&format!("WorkflowStage::{}(d) => Box::new({}::new(d))", enum_name, runner)).unwrap()
         }).collect::<Vec<_>>();
 
     let generated_source = quote::quote! {
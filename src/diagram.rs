@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+
+use crate::{schema::{Workflow, WorkflowStage, WorkflowStageData}, validate};
+use crate::workflows::stages::for_each::ForEachSource;
+
+// Renders a workflow as a graph: stages are nodes colored by their type, solid edges show
+// the order stages run in and dashed edges go from a stage to the stages using its output.
+// Block stages are drawn as a group holding their own node and the stages inside them.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Category {
+    Ai,
+    Command,
+    Io,
+    Data,
+    Control,
+    Fail,
+}
+
+impl Category {
+    const ALL: [Category; 6] = [Category::Ai, Category::Command, Category::Io, Category::Data, Category::Control, Category::Fail];
+
+    fn of(stage: &WorkflowStage) -> Self {
+        match stage {
+            WorkflowStage::AiProcessing(_) | WorkflowStage::AiReshape(_) | WorkflowStage::ToJson(_)
+            | WorkflowStage::MapReduce(_) | WorkflowStage::FeedbackLoop(_) => Category::Ai,
+            WorkflowStage::ShellCommand(_) | WorkflowStage::ShellScript(_) | WorkflowStage::PythonScript(_)
+            | WorkflowStage::Plugin(_) => Category::Command,
+            WorkflowStage::UserInput(_) | WorkflowStage::Echo(_) | WorkflowStage::Print(_) | WorkflowStage::LogWarn(_)
+            | WorkflowStage::SaveFile(_) | WorkflowStage::LoadFile(_) => Category::Io,
+            WorkflowStage::Set(_) | WorkflowStage::Split(_) => Category::Data,
+            WorkflowStage::Fail(_) => Category::Fail,
+            _ => Category::Control,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Category::Ai => "ai",
+            Category::Command => "command",
+            Category::Io => "io",
+            Category::Data => "data",
+            Category::Control => "control",
+            Category::Fail => "fail",
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            Category::Ai => "#d9ccf5",
+            Category::Command => "#f9d9ae",
+            Category::Io => "#c4e4f5",
+            Category::Data => "#d5eecb",
+            Category::Control => "#e8e8e8",
+            Category::Fail => "#f5c4c4",
+        }
+    }
+}
+
+struct Node<'a> {
+    name: &'a str,
+    type_name: String,
+    category: Category,
+}
+
+enum Item {
+    Node(usize),
+    Group { label: String, items: Vec<Item> },
+}
+
+#[derive(PartialEq, Eq)]
+struct Edge {
+    from: usize,
+    to: usize,
+    // Data dependencies are dashed, the order stages run in is solid
+    data: bool,
+}
+
+#[derive(Default)]
+struct Diagram<'a> {
+    nodes: Vec<Node<'a>>,
+    items: Vec<Item>,
+    edges: Vec<Edge>,
+    // Node that last defined every name, to draw data dependencies
+    defined: HashMap<&'a str, usize>,
+}
+
+impl<'a> Diagram<'a> {
+    fn new(workflow: &'a Workflow) -> Self {
+        let mut diagram = Self::default();
+        diagram.items = diagram.stages(&workflow.stages);
+        diagram
+    }
+
+    fn edge(&mut self, from: usize, to: usize, data: bool) {
+        let edge = Edge { from, to, data };
+        if from != to && !self.edges.contains(&edge) {
+            self.edges.push(edge);
+        }
+    }
+
+    // Adds stages that run one after another, with edges between them
+    fn stages(&mut self, stages: &'a [WorkflowStageData]) -> Vec<Item> {
+        let mut items = Vec::new();
+        let mut previous = None;
+        for stage in stages {
+            let (id, item) = self.stage(stage);
+            if let Some(previous) = previous {
+                self.edge(previous, id, false);
+            }
+            previous = Some(id);
+            items.push(item);
+        }
+        items
+    }
+
+    fn stage(&mut self, stage: &'a WorkflowStageData) -> (usize, Item) {
+        let id = self.nodes.len();
+        self.nodes.push(Node { name: &stage.name, type_name: stage.stage.type_name(), category: Category::of(&stage.stage) });
+
+        let mut references = validate::stage_references(stage);
+        if let WorkflowStage::ForEach(info) = &stage.stage {
+            if let ForEachSource::List(list) = &info.source {
+                references.push(list.clone());
+            }
+        }
+        for name in references {
+            // `${call.summary}` uses the output of the `call` stage
+            let prefix = name.split_once('.').map_or(name.as_str(), |(prefix, _)| prefix);
+            if let Some(&from) = self.defined.get(name.as_str()).or_else(|| self.defined.get(prefix)) {
+                self.edge(from, id, true);
+            }
+        }
+
+        let lists = lists(&stage.stage);
+        let item = if lists.is_empty() {
+            Item::Node(id)
+        } else {
+            let mut items = vec![Item::Node(id)];
+            let single = lists.len() == 1;
+            for (label, stages) in lists {
+                let first = self.nodes.len();
+                let inner = self.stages(stages);
+                if !stages.is_empty() {
+                    self.edge(id, first, false);
+                }
+                match single {
+                    true => items.extend(inner),
+                    false => items.push(Item::Group { label, items: inner }),
+                }
+            }
+            Item::Group { label: format!("{} ({})", stage.name, stage.stage.type_name()), items }
+        };
+        for name in stage.defined_names() {
+            self.defined.insert(name, id);
+        }
+        (id, item)
+    }
+}
+
+// Stage lists of a block stage with a label for each, empty lists are left out
+fn lists(stage: &WorkflowStage) -> Vec<(String, &[WorkflowStageData])> {
+    let lists: Vec<(String, &[WorkflowStageData])> = match stage {
+        WorkflowStage::ForEach(info) => vec![(String::new(), &info.stages)],
+        WorkflowStage::Until(info) => vec![(String::new(), &info.stages)],
+        WorkflowStage::IfElse(info) => vec![("if".to_string(), &info.if_stages), ("else".to_string(), &info.else_stages)],
+        WorkflowStage::Try(info) => std::iter::once(("try".to_string(), info.stages.as_slice()))
+            .chain(info.catch.iter().enumerate().map(|(i, clause)| (format!("catch {}", i + 1), clause.stages.as_slice())))
+            .chain(std::iter::once(("finally".to_string(), info.finally.as_slice())))
+            .collect(),
+        WorkflowStage::Switch(info) => info.cases.iter().enumerate()
+            .map(|(i, case)| (format!("case {}", i + 1), case.stages.as_slice()))
+            .chain(std::iter::once(("default".to_string(), info.default.as_slice())))
+            .collect(),
+        WorkflowStage::Parallel(info) => info.branches.iter()
+            .map(|branch| (branch.name.clone(), branch.stages.as_slice()))
+            .collect(),
+        _ => vec![],
+    };
+    lists.into_iter().filter(|(_, stages)| !stages.is_empty()).collect()
+}
+
+pub fn mermaid(workflow: &Workflow) -> String {
+    let diagram = Diagram::new(workflow);
+    let mut out = String::from("flowchart TD\n");
+    for category in Category::ALL {
+        out.push_str(&format!("    classDef {} fill:{},stroke:#555\n", category.name(), category.color()));
+    }
+    let mut groups = 0;
+    mermaid_items(&diagram, &diagram.items, 1, &mut groups, &mut out);
+    for edge in &diagram.edges {
+        let arrow = if edge.data { "-.->" } else { "-->" };
+        out.push_str(&format!("    n{} {} n{}\n", edge.from, arrow, edge.to));
+    }
+    out
+}
+
+fn mermaid_items(diagram: &Diagram, items: &[Item], depth: usize, groups: &mut usize, out: &mut String) {
+    let indent = "    ".repeat(depth);
+    for item in items {
+        match item {
+            Item::Node(id) => {
+                let node = &diagram.nodes[*id];
+                let label = format!("{}<br/><i>{}</i>", mermaid_escape(node.name), node.type_name);
+                out.push_str(&format!("{}n{}[\"{}\"]:::{}\n", indent, id, label, node.category.name()));
+            },
+            Item::Group { label, items } => {
+                *groups += 1;
+                out.push_str(&format!("{}subgraph g{} [\"{}\"]\n", indent, groups, mermaid_escape(label)));
+                mermaid_items(diagram, items, depth + 1, groups, out);
+                out.push_str(&format!("{}end\n", indent));
+            },
+        }
+    }
+}
+
+fn mermaid_escape(text: &str) -> String {
+    text.replace('"', "#quot;").replace('<', "#lt;").replace('>', "#gt;")
+}
+
+pub fn dot(workflow: &Workflow) -> String {
+    let diagram = Diagram::new(workflow);
+    let mut out = format!("digraph \"{}\" {{\n", dot_escape(&workflow.name));
+    out.push_str("    rankdir=TB;\n    node [shape=box, style=\"rounded,filled\", fontname=\"Helvetica\"];\n");
+    let mut groups = 0;
+    dot_items(&diagram, &diagram.items, 1, &mut groups, &mut out);
+    for edge in &diagram.edges {
+        let style = if edge.data { " [style=dashed, color=\"#777777\"]" } else { "" };
+        out.push_str(&format!("    n{} -> n{}{};\n", edge.from, edge.to, style));
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn dot_items(diagram: &Diagram, items: &[Item], depth: usize, groups: &mut usize, out: &mut String) {
+    let indent = "    ".repeat(depth);
+    for item in items {
+        match item {
+            Item::Node(id) => {
+                let node = &diagram.nodes[*id];
+                out.push_str(&format!("{}n{} [label=\"{}\\n{}\", fillcolor=\"{}\"];\n", indent, id, dot_escape(node.name), node.type_name, node.category.color()));
+            },
+            Item::Group { label, items } => {
+                *groups += 1;
+                out.push_str(&format!("{}subgraph cluster_{} {{\n{}    label=\"{}\";\n{}    style=dashed;\n", indent, groups, indent, dot_escape(label), indent));
+                dot_items(diagram, items, depth + 1, groups, out);
+                out.push_str(&format!("{}}}\n", indent));
+            },
+        }
+    }
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::Workflows;
+
+    use super::*;
+
+    fn workflows() -> Workflows {
+        serde_yaml::from_str(r#"
+workflows:
+  - name: test
+    stages:
+      - { name: items, stage: { type: split, data: "a,b", delimiter: ",", trim: true, remove_empty: true } }
+      - name: loop
+        stage:
+          type: for_each
+          list: items
+          variable: item
+          stages:
+            - { name: ask, stage: { type: ai_processing, model: gpt-4, system_message: "s", prompt: "${item}" } }
+      - { name: show, stage: { type: print, output: "${items}" } }
+"#).unwrap()
+    }
+
+    #[test]
+    fn test_mermaid() {
+        let workflows = workflows();
+        let diagram = mermaid(&workflows.workflows[0]);
+        assert!(diagram.contains("    subgraph g1 [\"loop (for_each)\"]\n        n1[\"loop<br/><i>for_each</i>\"]:::control\n        n2[\"ask<br/><i>ai_processing</i>\"]:::ai\n    end\n"));
+        let mut edges: Vec<&str> = diagram.lines().filter(|line| line.contains("->")).map(str::trim).collect();
+        edges.sort();
+        assert_eq!(edges, vec!["n0 --> n1", "n0 -.-> n1", "n0 -.-> n3", "n1 --> n2", "n1 --> n3"]);
+    }
+
+    #[test]
+    fn test_dot() {
+        let workflows = workflows();
+        let diagram = dot(&workflows.workflows[0]);
+        assert!(diagram.starts_with("digraph \"test\" {\n"));
+        assert!(diagram.contains("subgraph cluster_1 {"));
+        assert!(diagram.contains("n0 -> n3 [style=dashed"));
+    }
+}
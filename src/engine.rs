@@ -0,0 +1,221 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::de::DeserializeOwned;
+
+use crate::{
+    error::Error,
+    interface::{cli::CliInterface, Interface},
+    llm::{LlmProvider, OpenAi},
+    loader,
+    schema::{Workflow, Workflows},
+    workflows::{self, stages::StageOutput, RunOptions},
+};
+
+// Runs workflows from other programs, the command line is built on top of it:
+//
+//     let engine = Engine::builder()
+//         .workflows_file("yc-workflows.yaml")
+//         .llm(MyProvider::new())
+//         .build()?;
+//     let outputs = engine.run("summarize", [("file", "notes.md")]).await?;
+//     println!("{}", outputs.text("summary").unwrap_or_default());
+
+enum WorkflowsSource {
+    File(PathBuf),
+    Text(String),
+    Loaded(Workflows),
+}
+
+pub struct EngineBuilder {
+    source: Option<WorkflowsSource>,
+    interface: Box<dyn Interface>,
+    llm: Box<dyn LlmProvider>,
+    workdir: PathBuf,
+}
+
+impl EngineBuilder {
+    // Loads workflows from a file and its imports
+    pub fn workflows_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.source = Some(WorkflowsSource::File(path.into()));
+        self
+    }
+
+    // Loads workflows from YAML text, its imports are relative to the working directory
+    pub fn workflows_str<S: Into<String>>(mut self, yaml: S) -> Self {
+        self.source = Some(WorkflowsSource::Text(yaml.into()));
+        self
+    }
+
+    pub fn workflows(mut self, workflows: Workflows) -> Self {
+        self.source = Some(WorkflowsSource::Loaded(workflows));
+        self
+    }
+
+    // Asks for inputs and shows messages, on the terminal by default
+    pub fn interface<I: Interface + 'static>(mut self, interface: I) -> Self {
+        self.interface = Box::new(interface);
+        self
+    }
+
+    // Answers model calls, OpenAI by default, which needs `llm::load_token` first
+    pub fn llm<L: LlmProvider + 'static>(mut self, llm: L) -> Self {
+        self.llm = Box::new(llm);
+        self
+    }
+
+    // Directory commands run in and files are read from and saved to, the current directory by default
+    pub fn workdir<P: Into<PathBuf>>(mut self, workdir: P) -> Self {
+        self.workdir = workdir.into();
+        self
+    }
+
+    pub fn build(self) -> Result<Engine, Error> {
+        let workflows = match self.source {
+            Some(WorkflowsSource::File(path)) => loader::load(&path)?,
+            Some(WorkflowsSource::Text(yaml)) => loader::load_str(&yaml, &self.workdir)?,
+            Some(WorkflowsSource::Loaded(workflows)) => {
+                workflows.check()?;
+                workflows
+            },
+            None => return Err(Error::InvalidWorkflow("No workflows given to the engine".to_string())),
+        };
+        Ok(Engine {
+            workflows,
+            interface: self.interface,
+            llm: self.llm,
+            workdir: self.workdir,
+        })
+    }
+}
+
+pub struct Engine {
+    workflows: Workflows,
+    interface: Box<dyn Interface>,
+    llm: Box<dyn LlmProvider>,
+    workdir: PathBuf,
+}
+
+impl Engine {
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder {
+            source: None,
+            interface: Box::new(CliInterface),
+            llm: Box::new(OpenAi),
+            workdir: PathBuf::from("."),
+        }
+    }
+
+    pub fn workflows(&self) -> &Workflows {
+        &self.workflows
+    }
+
+    // The workflow with the given name, or the only one when no name is given
+    pub fn workflow(&self, name: Option<&str>) -> Result<&Workflow, Error> {
+        match name {
+            Some(name) => self.workflows.workflows.iter().find(|workflow| workflow.name == name)
+                .ok_or_else(|| Error::InvalidWorkflow(format!("No workflow found with the name: {}", name))),
+            None if self.workflows.workflows.len() == 1 => Ok(&self.workflows.workflows[0]),
+            None => Err(Error::InvalidWorkflow("No workflow name provided and there are multiple workflows in the file. Please provide the workflow name.".to_string())),
+        }
+    }
+
+    // Runs a workflow with the given inputs, the interface asks for the missing ones
+    pub async fn run<I, K, V>(&self, name: &str, inputs: I) -> Result<Outputs, Error>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let options = RunOptions {
+            variables: inputs.into_iter().map(|(name, value)| (name.into(), StageOutput::Text(value.into()))).collect(),
+            ..RunOptions::default()
+        };
+        self.run_with(Some(name), &options).await
+    }
+
+    pub async fn run_with(&self, name: Option<&str>, options: &RunOptions) -> Result<Outputs, Error> {
+        let workflow = self.workflow(name)?;
+        let variables = workflows::run_workflow(&self.workflows, workflow, self.interface.as_ref(), self.llm.as_ref(), &self.workdir, options).await?;
+        Ok(Outputs(workflows::workflow_outputs(workflow, variables).into_iter().collect()))
+    }
+}
+
+// The declared outputs of a finished workflow, or all of its variables when it declares none
+#[derive(Debug, Clone, Default)]
+pub struct Outputs(HashMap<String, StageOutput>);
+
+impl Outputs {
+    pub fn get(&self, name: &str) -> Option<&StageOutput> {
+        self.0.get(name)
+    }
+
+    pub fn text(&self, name: &str) -> Option<&str> {
+        match self.0.get(name) {
+            Some(StageOutput::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn list(&self, name: &str) -> Option<&[String]> {
+        match self.0.get(name) {
+            Some(StageOutput::List(list)) => Some(list),
+            _ => None,
+        }
+    }
+
+    // Parses a text output as JSON, like the output of a `to_json` stage
+    pub fn json<T: DeserializeOwned>(&self, name: &str) -> Result<T, Error> {
+        let text = self.text(name)
+            .ok_or_else(|| Error::VariableTypeMismatch(format!("Output {} is not text", name)))?;
+        serde_json::from_str(text)
+            .map_err(|e| Error::VariableTypeMismatch(format!("Output {} is not valid JSON: {}", name, e)))
+    }
+
+    pub fn into_inner(self) -> HashMap<String, StageOutput> {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use crate::llm::{Message, Response};
+
+    use super::*;
+
+    struct Echo;
+
+    #[async_trait]
+    impl LlmProvider for Echo {
+        async fn complete(&self, messages: Vec<Message>, model: &str) -> Result<Response, Error> {
+            Ok(Response { text: format!("{} says {:?}", model, messages.last().unwrap()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::builder()
+            .workflows_str(r#"
+workflows:
+  - name: greet
+    inputs: [{ name: who }]
+    outputs: [answer, parts]
+    stages:
+      - { name: answer, stage: { type: ai_processing, model: gpt-4, system_message: "s", prompt: "Hello ${who}" } }
+      - { name: parts, stage: { type: split, data: "a,b", delimiter: ",", trim: true, remove_empty: true } }
+"#)
+            .llm(Echo)
+            .workdir(dir.path())
+            .build()
+            .unwrap();
+        let outputs = engine.run("greet", [("who", "Ada")]).await.unwrap();
+        assert_eq!(outputs.text("answer"), Some(r#"gpt-4 says UserMessage("Hello Ada")"#));
+        assert_eq!(outputs.list("parts"), Some(&["a".to_string(), "b".to_string()][..]));
+        assert!(outputs.get("who").is_none());
+        assert!(engine.workflow(Some("missing")).is_err());
+        // Only runs with `save_state` are saved
+        assert!(!dir.path().join(".yamlchain").exists());
+    }
+}
@@ -1,12 +1,27 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     InvalidEnvironment(String),
+    InvalidWorkflow(String),
     OpenAIError(String),
     InterpolationError,
+    TemplateError(String),
     VariableNotFound(String),
     RuntimeError(String),
+    ValidationFailed(String),
     VariableTypeMismatch(String),
     MaxIterationsExceeded,
+    Timeout(String),
+    MultipleErrors(Vec<Error>),
+    Aborted(String),
+    Interrupted,
+    Failed {
+        message: String,
+        exit_code: Option<u8>,
+    },
     StageError {
         stage_name: String,
         error: Box<Error>,
@@ -20,21 +35,160 @@ impl Error {
             error: Box::new(self),
         }
     }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::InvalidEnvironment(_) => ErrorKind::InvalidEnvironment,
+            Error::InvalidWorkflow(_) => ErrorKind::InvalidWorkflow,
+            Error::OpenAIError(_) => ErrorKind::Openai,
+            Error::InterpolationError | Error::TemplateError(_) => ErrorKind::Template,
+            Error::VariableNotFound(_) => ErrorKind::VariableNotFound,
+            Error::RuntimeError(_) => ErrorKind::Runtime,
+            Error::ValidationFailed(_) => ErrorKind::Validation,
+            Error::VariableTypeMismatch(_) => ErrorKind::TypeMismatch,
+            Error::MaxIterationsExceeded => ErrorKind::MaxIterations,
+            Error::Timeout(_) => ErrorKind::Timeout,
+            Error::MultipleErrors(_) => ErrorKind::Multiple,
+            Error::Aborted(_) => ErrorKind::Aborted,
+            Error::Interrupted => ErrorKind::Interrupted,
+            Error::Failed { .. } => ErrorKind::Failed,
+            Error::StageError { error, .. } => error.kind(),
+        }
+    }
+
+    // Exit code of the process: 1 for failed stages, 2 for invalid workflows or environment,
+    // 3 when the user aborted, 4 when a limit was exceeded, 130 when interrupted with Ctrl-C,
+    // or the code given to a `fail` stage
+    pub fn exit_code(&self) -> u8 {
+        if self.is_interrupted() {
+            return 130;
+        }
+        match self.root() {
+            Error::InvalidWorkflow(_) | Error::InvalidEnvironment(_) => 2,
+            Error::Aborted(_) => 3,
+            Error::MaxIterationsExceeded => 4,
+            Error::Failed { exit_code, .. } => exit_code.unwrap_or(1),
+            _ => 1,
+        }
+    }
+
+    // Interruptions stop the run even when they happened next to other errors, so they are never caught or skipped
+    pub fn is_interrupted(&self) -> bool {
+        match self.root() {
+            Error::Interrupted => true,
+            Error::MultipleErrors(errors) => errors.iter().any(Error::is_interrupted),
+            _ => false,
+        }
+    }
+
+    // The error without the stages it was raised in
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::StageError { error, .. } => error.root(),
+            error => error,
+        }
+    }
+
+    // Names of the stages the error was raised in, from the outermost to the one that failed
+    pub fn stage_path(&self) -> Vec<&str> {
+        let mut path = vec![];
+        let mut error = self;
+        while let Error::StageError { stage_name, error: inner } = error {
+            path.push(stage_name.as_str());
+            error = inner;
+        }
+        path
+    }
+}
+
+/// Category of an error, used to catch only some errors in a `try` stage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    InvalidEnvironment,
+    InvalidWorkflow,
+    Openai,
+    Template,
+    VariableNotFound,
+    Runtime,
+    /// The output of a stage did not pass the `validate` condition of its retry policy
+    Validation,
+    TypeMismatch,
+    MaxIterations,
+    Timeout,
+    /// Several errors of a parallel stage or a loop
+    Multiple,
+    /// The user ended the input, for example by quitting vim with `:cq`
+    Aborted,
+    /// Raised by a `fail` stage
+    Failed,
+    /// The run was stopped with Ctrl-C, a `try` stage never catches it
+    Interrupted,
+}
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::InvalidEnvironment => "invalid_environment",
+            ErrorKind::InvalidWorkflow => "invalid_workflow",
+            ErrorKind::Openai => "openai",
+            ErrorKind::Template => "template",
+            ErrorKind::VariableNotFound => "variable_not_found",
+            ErrorKind::Runtime => "runtime",
+            ErrorKind::Validation => "validation",
+            ErrorKind::TypeMismatch => "type_mismatch",
+            ErrorKind::MaxIterations => "max_iterations",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Multiple => "multiple",
+            ErrorKind::Aborted => "aborted",
+            ErrorKind::Failed => "failed",
+            ErrorKind::Interrupted => "interrupted",
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Error::InvalidEnvironment(env) => write!(f, "Invalid environment: {}", env),
+            Error::InvalidWorkflow(msg) => write!(f, "Invalid workflow: {}", msg),
             Error::OpenAIError(msg) => write!(f, "OpenAI error: {}", msg),
-            Error::StageError { stage_name, error } => {
-                write!(f, "Error in stage {}: {}", stage_name, error)
+            Error::StageError { .. } => {
+                write!(f, "Error in stage {}: {}", self.stage_path().join(" > "), self.root())
             },
             Error::InterpolationError => write!(f, "Interpolation error"),
+            Error::TemplateError(msg) => write!(f, "Template error: {}", msg),
             Error::VariableNotFound(var_name) => write!(f, "Variable not found: {}", var_name),
             Error::VariableTypeMismatch(msg) => write!(f, "Variable type mismatch: {}", msg),
             Error::RuntimeError(msg) => write!(f, "Runtime error: {}", msg),
+            Error::ValidationFailed(msg) => write!(f, "Validation failed: {}", msg),
             Error::MaxIterationsExceeded => write!(f, "Max iterations exceeded"),
+            Error::Timeout(msg) => write!(f, "Timeout: {}", msg),
+            Error::MultipleErrors(errors) => {
+                let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{} errors: {}", errors.len(), messages.join("; "))
+            },
+            Error::Aborted(msg) => write!(f, "Aborted: {}", msg),
+            Error::Interrupted => write!(f, "Interrupted"),
+            Error::Failed { message, .. } => write!(f, "Failed: {}", message),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_error() {
+        let error = Error::Failed { message: "boom".to_string(), exit_code: Some(7) }
+            .at_stage("inner")
+            .at_stage("outer");
+        assert_eq!(error.to_string(), "Error in stage outer > inner: Failed: boom");
+        assert_eq!(error.kind(), ErrorKind::Failed);
+        assert_eq!(error.stage_path(), vec!["outer", "inner"]);
+        assert_eq!(error.exit_code(), 7);
+        assert_eq!(Error::MaxIterationsExceeded.at_stage("loop").exit_code(), 4);
+        assert_eq!(Error::MultipleErrors(vec![Error::Interrupted.at_stage("inner")]).at_stage("loop").exit_code(), 130);
+    }
+}
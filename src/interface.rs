@@ -10,7 +10,7 @@ pub trait Interface: Send + Sync {
     async fn get_input(&self, msg: String) -> Result<String, Error>;
 }
 
-impl<'a> std::fmt::Debug for &'a dyn Interface {
+impl std::fmt::Debug for &dyn Interface {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Interface").finish()
     }
@@ -14,6 +14,12 @@ impl CliInterface {
     }
 }
 
+impl Default for CliInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[async_trait]
 impl Interface for CliInterface {
     async fn send_message(&self, msg: String) -> Result<(), Error> {
@@ -21,9 +27,20 @@ impl Interface for CliInterface {
         Ok(())
     }
     async fn get_input(&self, msg: String) -> Result<String, Error> {
-        let mut input = String::new();
         println!("{}", msg);
-        std::io::stdin().read_line(&mut input).map_err(|e| Error::RuntimeError(e.to_string()))?;
+        // Reads on its own thread, so an interrupted run does not wait for the line
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        std::thread::spawn(move || {
+            let mut input = String::new();
+            let result = std::io::stdin().read_line(&mut input).map(|read| (read, input));
+            sender.send(result).ok();
+        });
+        let (read, input) = receiver.await
+            .map_err(|e| Error::RuntimeError(e.to_string()))?
+            .map_err(|e| Error::RuntimeError(e.to_string()))?;
+        if read == 0 {
+            return Err(Error::Aborted("End of input".to_string()));
+        }
         Ok(input)
     }
 }
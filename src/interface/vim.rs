@@ -1,9 +1,9 @@
 use std::fs::File;
 use std::io::{Read, Write};
-use std::process::Command;
 
 use async_trait::async_trait;
 use tempfile::NamedTempFile;
+use tokio::process::Command;
 
 use crate::error::Error;
 
@@ -22,6 +22,12 @@ impl VimInterface {
     }
 }
 
+impl Default for VimInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[async_trait]
 impl Interface for VimInterface {
     async fn send_message(&self, msg: String) -> Result<(), Error> {
@@ -35,10 +41,16 @@ impl Interface for VimInterface {
         let mut tmp_file = NamedTempFile::new().map_err(|e| Error::RuntimeError(e.to_string()))?;
         tmp_file.write_all(prepared_message.as_bytes()).map_err(|e| Error::RuntimeError(e.to_string()))?;
 
-        Command::new("vim")
+        // Interrupting the run drops this future, which closes vim and removes the temp file
+        let status = Command::new("vim")
             .arg(tmp_file.path())
+            .kill_on_drop(true)
             .status()
+            .await
             .map_err(|e| Error::RuntimeError(e.to_string()))?;
+        if !status.success() {
+            return Err(Error::Aborted(format!("Editor exited with {}", status)));
+        }
 
         let mut content = String::new();
         File::open(tmp_file.path()).and_then(|mut file| file.read_to_string(&mut content))
@@ -0,0 +1,16 @@
+pub mod diagram;
+pub mod engine;
+pub mod error;
+pub mod interface;
+pub mod llm;
+pub mod loader;
+pub mod schema;
+pub mod validate;
+pub mod workflows;
+mod generated;
+
+pub use engine::{Engine, EngineBuilder, Outputs};
+pub use error::Error;
+pub use interface::Interface;
+pub use llm::LlmProvider;
+pub use workflows::{stages::StageOutput, RunOptions};
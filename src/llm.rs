@@ -1,11 +1,18 @@
+use async_trait::async_trait;
 use openai::chat::{ChatCompletionMessageRole, ChatCompletionMessage};
+use serde::Serialize;
 
 use crate::error::Error;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "role", content = "text")]
+#[allow(clippy::enum_variant_names)]
 pub enum Message {
+    #[serde(rename = "user")]
     UserMessage(String),
+    #[serde(rename = "system")]
     SystemMessage(String),
+    #[serde(rename = "assistant")]
     AiMessage(String),
 }
 
@@ -21,6 +28,7 @@ impl Message {
     pub fn user<S: Into<String>>(s: S) -> Self {
         Self::UserMessage(s.into())
     }
+    #[allow(dead_code)]
     pub fn ai<S: Into<String>>(s: S) -> Self {
         Self::AiMessage(s.into())
     }
@@ -43,6 +51,29 @@ impl Message {
     }
 }
 
+// Answers the messages of `ai_*` stages, implement it to use another model provider or to answer in tests
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    async fn complete(&self, messages: Vec<Message>, model: &str) -> Result<Response, Error>;
+}
+
+impl std::fmt::Debug for &dyn LlmProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LlmProvider").finish()
+    }
+}
+
+// Calls the OpenAI chat API with the token set by `load_token`
+#[derive(Debug, Default)]
+pub struct OpenAi;
+
+#[async_trait]
+impl LlmProvider for OpenAi {
+    async fn complete(&self, messages: Vec<Message>, model: &str) -> Result<Response, Error> {
+        call_openai(messages, model).await
+    }
+}
+
 pub fn load_token() -> Result<(), Error> {
     let openai_api_key = std::env::var("OPENAI_API_KEY").map_err(|_| {
         Error::InvalidEnvironment(String::from("Missing OPENAI_API_KEY environment variable"))
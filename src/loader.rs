@@ -0,0 +1,283 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use regex::Regex;
+use serde_yaml::{Mapping, Value};
+
+use crate::{error::Error, schema::{Import, Workflows}};
+
+// Loads a workflows file together with everything it imports.
+// Imported workflows, prompts and stage templates are renamed to `<namespace>.<name>`,
+// and stages using a `template` get the fields of the template they do not set themselves.
+pub fn load(path: &Path) -> Result<Workflows, Error> {
+    let (workflows, _) = load_unchecked(path)?;
+    workflows.check()?;
+    Ok(workflows)
+}
+
+// Loads workflows from YAML text, with imports relative to `dir`
+pub fn load_str(content: &str, dir: &Path) -> Result<Workflows, Error> {
+    let library: Mapping = serde_yaml::from_str(content)
+        .map_err(|e| Error::InvalidWorkflow(e.to_string()))?;
+    let library = resolve(library, "workflows", dir, &mut vec![], &mut vec![])?;
+    let workflows: Workflows = serde_yaml::from_value(Value::Mapping(library))
+        .map_err(|e| Error::InvalidWorkflow(e.to_string()))?;
+    workflows.check()?;
+    Ok(workflows)
+}
+
+// The file a workflow is defined in and its name in that file, before it was prefixed with a namespace
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+// Loads workflows without checking them, together with the source of every workflow by name
+pub fn load_unchecked(path: &Path) -> Result<(Workflows, HashMap<String, Source>), Error> {
+    let mut sources = Vec::new();
+    let library = load_file(path, &mut vec![], &mut sources)?;
+    let workflows: Workflows = serde_yaml::from_value(Value::Mapping(library))
+        .map_err(|e| Error::InvalidWorkflow(format!("{}: {}", path.display(), e)))?;
+    Ok((workflows, sources.into_iter().collect()))
+}
+
+fn load_file(path: &Path, stack: &mut Vec<PathBuf>, sources: &mut Vec<(String, Source)>) -> Result<Mapping, Error> {
+    let canonical = path.canonicalize()
+        .map_err(|e| Error::InvalidWorkflow(format!("Cannot open {}: {}", path.display(), e)))?;
+    if stack.contains(&canonical) {
+        let cycle: Vec<String> = stack.iter().chain(std::iter::once(&canonical))
+            .map(|path| path.display().to_string())
+            .collect();
+        return Err(Error::InvalidWorkflow(format!("Import cycle: {}", cycle.join(" -> "))));
+    }
+    let file = std::fs::File::open(&canonical)
+        .map_err(|e| Error::InvalidWorkflow(format!("Cannot open {}: {}", path.display(), e)))?;
+    let library: Mapping = serde_yaml::from_reader(file)
+        .map_err(|e| Error::InvalidWorkflow(format!("{}: {}", path.display(), e)))?;
+    if let Some(Value::Sequence(workflows)) = library.get(&key("workflows")) {
+        let names = workflows.iter().filter_map(|workflow| workflow.get("name")?.as_str());
+        sources.extend(names.map(|name| (name.to_string(), Source { path: canonical.clone(), name: name.to_string() })));
+    }
+    stack.push(canonical);
+    let library = resolve(library, &path.display().to_string(), path.parent().unwrap_or(Path::new(".")), stack, sources)?;
+    stack.pop();
+    Ok(library)
+}
+
+// Adds the imports of a file, with `dir` the directory their paths are relative to, and applies stage templates
+fn resolve(mut library: Mapping, origin: &str, dir: &Path, stack: &mut Vec<PathBuf>, sources: &mut Vec<(String, Source)>) -> Result<Mapping, Error> {
+    if let Some(Value::Mapping(plugins)) = library.get_mut(&key("plugins")) {
+        for (_, plugin) in plugins.iter_mut() {
+            if let Some(Value::String(command)) = plugin.get_mut("command") {
+                // Plugins run in the working directory, so their paths are made absolute
+                if command.starts_with('.') {
+                    let path = std::path::absolute(dir.join(&*command))
+                        .map_err(|e| Error::InvalidWorkflow(format!("{}: cannot resolve plugin {}: {}", origin, command, e)))?;
+                    *command = path.to_string_lossy().to_string();
+                }
+            }
+        }
+    }
+    let imports: Vec<Import> = match library.get(&key("imports")) {
+        Some(imports) => serde_yaml::from_value(imports.clone())
+            .map_err(|e| Error::InvalidWorkflow(format!("{}: {}", origin, e)))?,
+        None => vec![],
+    };
+    for import in &imports {
+        let import_path = dir.join(&import.path);
+        let first = sources.len();
+        let imported = load_file(&import_path, stack, sources)?;
+        let namespace = import.namespace(&import_path);
+        for (name, _) in &mut sources[first..] {
+            *name = format!("{}.{}", namespace, name);
+        }
+        merge(&mut library, namespaced(imported, &namespace));
+    }
+
+    let templates = library.get(&key("stage_templates")).cloned().unwrap_or(Value::Null);
+    if let Some(workflows) = library.get_mut(&key("workflows")) {
+        apply_templates(workflows, &templates, &mut vec![])?;
+    }
+    Ok(library)
+}
+
+fn key(name: &str) -> Value {
+    Value::String(name.to_string())
+}
+
+// Adds the workflows, prompts, stage templates and plugins of an imported file to the importing one
+fn merge(library: &mut Mapping, imported: Mapping) {
+    for section in ["workflows", "prompts", "stage_templates", "plugins"] {
+        let values = match imported.get(&key(section)) {
+            Some(values) => values.clone(),
+            None => continue,
+        };
+        let target = library.entry(key(section)).or_insert(Value::Null);
+        match (target, values) {
+            (Value::Sequence(target), Value::Sequence(values)) => target.extend(values),
+            (Value::Mapping(target), Value::Mapping(values)) => target.extend(values),
+            (target, values) => *target = values,
+        }
+    }
+}
+
+// Renames everything an imported file defines, including the references between its own definitions
+fn namespaced(mut library: Mapping, namespace: &str) -> Mapping {
+    let prefix = |name: &str| format!("{}.{}", namespace, name);
+    let plugins: Vec<Value> = match library.get(&key("plugins")) {
+        Some(Value::Mapping(plugins)) => plugins.iter().map(|(name, _)| name.clone()).collect(),
+        _ => vec![],
+    };
+    if let Some(Value::Sequence(workflows)) = library.get_mut(&key("workflows")) {
+        for workflow in workflows.iter_mut() {
+            if let Some(Value::String(name)) = workflow.get_mut("name") {
+                *name = prefix(name);
+            }
+            rename_references(workflow, namespace, &plugins);
+        }
+    }
+    for section in ["prompts", "stage_templates", "plugins"] {
+        if let Some(Value::Mapping(values)) = library.get_mut(&key(section)) {
+            *values = std::mem::take(values).into_iter()
+                .map(|(name, mut value)| {
+                    rename_references(&mut value, namespace, &plugins);
+                    match name {
+                        Value::String(name) => (Value::String(prefix(&name)), value),
+                        name => (name, value),
+                    }
+                })
+                .collect();
+        }
+    }
+    library
+}
+
+// Prefixes workflows called with `call_workflow`, stage templates, plugins registered in the
+// imported file, and `${prompts.<name>}` and `vars["prompts.<name>"]` references
+fn rename_references(value: &mut Value, namespace: &str, plugins: &[Value]) {
+    match value {
+        Value::String(s) => {
+            // Jinja templates reach prompts through the `vars` map, like `vars["prompts.style"]`
+            let re = Regex::new(r#"(\$\{|\bvars\[\s*["'])prompts\."#).unwrap();
+            *s = re.replace_all(s, format!("${{1}}prompts.{}.", namespace).as_str()).to_string();
+        },
+        Value::Sequence(values) => values.iter_mut().for_each(|v| rename_references(v, namespace, plugins)),
+        Value::Mapping(mapping) => {
+            let is_call = mapping.get(&key("type")) == Some(&key("call_workflow"));
+            let is_plugin = mapping.get(&key("type")) == Some(&key("plugin"));
+            let is_stage = mapping.contains_key(&key("name"));
+            for (name, value) in mapping.iter_mut() {
+                match value {
+                    Value::String(workflow) if is_call && name == &key("workflow") => {
+                        *workflow = format!("{}.{}", namespace, workflow);
+                    },
+                    Value::String(template) if is_stage && name == &key("template") => {
+                        *template = format!("{}.{}", namespace, template);
+                    },
+                    Value::String(plugin) if is_plugin && name == &key("plugin") && plugins.contains(&key(plugin)) => {
+                        *plugin = format!("{}.{}", namespace, plugin);
+                    },
+                    value => rename_references(value, namespace, plugins),
+                }
+            }
+        },
+        _ => {},
+    }
+}
+
+// Replaces `template: <name>` in stages with the fields of the template, `stack` holds the
+// templates being applied to detect templates that use themselves.
+fn apply_templates(value: &mut Value, templates: &Value, stack: &mut Vec<String>) -> Result<(), Error> {
+    match value {
+        Value::Sequence(values) => {
+            for value in values {
+                apply_templates(value, templates, stack)?;
+            }
+        },
+        Value::Mapping(mapping) => {
+            let template_name = match mapping.get(&key("template")) {
+                Some(Value::String(name)) if mapping.contains_key(&key("name")) => Some(name.clone()),
+                _ => None,
+            };
+            if let Some(name) = template_name {
+                if stack.contains(&name) {
+                    return Err(Error::InvalidWorkflow(format!("Stage template {} uses itself", name)));
+                }
+                let mut template = match templates.get(&name) {
+                    Some(Value::Mapping(template)) => Value::Mapping(template.clone()),
+                    _ => return Err(Error::InvalidWorkflow(format!("Stage template {} does not exist", name))),
+                };
+                stack.push(name);
+                apply_templates(&mut template, templates, stack)?;
+                stack.pop();
+                if let Value::Mapping(template) = template {
+                    for (field, value) in template {
+                        mapping.entry(field).or_insert(value);
+                    }
+                }
+            }
+            for (_, value) in mapping.iter_mut() {
+                apply_templates(value, templates, stack)?;
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_namespaced_imports() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "lib.yaml", r#"
+prompts:
+  style: terse
+stage_templates:
+  greet:
+    stage: { type: set, value: "${prompts.style}" }
+  jinja:
+    stage: { type: set, engine: jinja, value: "{{ vars['prompts.style'] }} {{ vars[\"prompts.style\"] | upper }}" }
+workflows:
+  - name: outer
+    stages:
+      - { name: call, stage: { type: call_workflow, workflow: inner } }
+  - name: inner
+    stages:
+      - { name: hello, template: greet }
+"#);
+        let main = write(dir.path(), "main.yaml", r#"
+imports:
+  - path: lib.yaml
+workflows:
+  - name: main
+    stages:
+      - { name: hello, template: lib.greet }
+"#);
+        let workflows = load(&main).unwrap();
+        let names: Vec<&str> = workflows.workflows.iter().map(|workflow| workflow.name.as_str()).collect();
+        assert_eq!(names, vec!["main", "lib.outer", "lib.inner"]);
+        assert_eq!(workflows.prompts.get("lib.style").map(String::as_str), Some("terse"));
+        let serialized = serde_json::to_string(&workflows.workflows).unwrap();
+        assert!(serialized.contains(r#""workflow":"lib.inner""#));
+        assert!(serialized.contains("${prompts.lib.style}"));
+        let templates = serde_json::to_string(&workflows.stage_templates).unwrap();
+        assert!(templates.contains(r#"{{ vars['prompts.lib.style'] }} {{ vars[\"prompts.lib.style\"] | upper }}"#), "{}", templates);
+    }
+
+    #[test]
+    fn test_import_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.yaml", "imports: [{ path: b.yaml }]\nworkflows: []\n");
+        write(dir.path(), "b.yaml", "imports: [{ path: a.yaml }]\nworkflows: []\n");
+        assert!(load(&dir.path().join("a.yaml")).is_err());
+    }
+}
@@ -1,21 +1,21 @@
+use std::collections::HashMap;
 use std::io::Write;
+use std::process::ExitCode;
+use std::sync::Arc;
 
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 
-use crate::interface::Interface;
-mod error;
-mod llm;
-mod schema;
-mod workflows;
-mod interface;
-mod generated;
+use yamlchain::{diagram, interface, llm, schema, validate, workflows, Engine, Error, StageOutput};
+use yamlchain::workflows::{cancel::Cancellation, checkpoint::RunState, dry_run::DryRun};
 
 #[derive(Parser)]
 #[command(version = "1.0", author = "Szymon Dziwak <skdziwak@gmail.com>", about = "This is an application that allows you to create an AI assistant for a specific task.")]
 struct Cli {
     #[arg(short, long, help = "Saves JSON schema for the workflows file")]
     schema: Option<String>,
-    #[arg(short = 'f', long, help = "Path to the workflows file")]
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(short = 'f', long, global = true, help = "Path to the workflows file")]
     workflows_file: Option<String>,
     #[arg(name = "workflow_name", help = "Name of the workflow to run, if not specified and there is only one workflow in the file, it will be used")]
     name: Option<String>,
@@ -23,27 +23,85 @@ struct Cli {
     interface: Option<InterfaceSelection>,
     #[arg(short, long, help = "Working directory for the workflow, if not specified, current directory will be used.")]
     workdir: Option<String>,
+    #[arg(long, help = "Continues a failed or interrupted run from its first unfinished stage")]
+    resume: Option<String>,
+    #[arg(long, help = "Shows the prompts, commands and files of stages with side effects instead of running them")]
+    dry_run: bool,
+    #[arg(long, requires = "dry_run", help = "YAML file with outputs of stages skipped by --dry-run, by stage name")]
+    dry_run_outputs: Option<String>,
+    #[arg(long, conflicts_with_all = ["resume", "only"], help = "Starts the run at this top-level stage")]
+    from: Option<String>,
+    #[arg(long, conflicts_with_all = ["resume", "only"], help = "Stops the run after this top-level stage")]
+    until: Option<String>,
+    #[arg(long, conflicts_with = "resume", help = "Runs only this top-level stage")]
+    only: Option<String>,
+    #[arg(long = "var", conflicts_with = "resume", value_name = "NAME=VALUE", value_parser = parse_assignment, help = "Sets a variable before the run, can be repeated")]
+    vars: Vec<(String, String)>,
+    #[arg(long = "var-file", conflicts_with = "resume", value_name = "NAME=PATH", value_parser = parse_assignment, help = "Sets a variable to the content of a file, can be repeated")]
+    var_files: Vec<(String, String)>,
+    #[arg(long, value_name = "RUN_ID", conflicts_with = "resume", help = "Sets the variables saved by a previous run before the run")]
+    vars_from: Option<String>,
+    #[arg(long, help = "Writes the stages, prompts, model responses and errors of the run to .yamlchain/journals/<run id>.jsonl")]
+    journal: bool,
     #[arg(short, long, help = "Enable debug logs")]
     debug: bool,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Checks the workflows file and its imports for mistakes without running anything
+    Validate,
+    /// Prints a workflow as a Mermaid or Graphviz (DOT) diagram
+    Diagram {
+        #[arg(help = "Name of the workflow, needed when the file has more than one")]
+        workflow: Option<String>,
+        #[arg(long, value_enum, default_value = "mermaid")]
+        format: DiagramFormat,
+    },
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy)]
+enum DiagramFormat {
+    Mermaid,
+    Dot,
+}
+
 #[derive(Debug, ValueEnum, Clone)]
 enum InterfaceSelection {
     Cli,
     Vim,
 }
 
+fn parse_assignment(value: &str) -> Result<(String, String), String> {
+    value.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("Expected NAME=VALUE, got {}", value))
+}
+
+// The first Ctrl-C stops the running stages and saves the run, the second one exits right away
+async fn cancel_on_ctrl_c(cancellation: Arc<Cancellation>) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return;
+    }
+    log::warn!("Interrupted, stopping the running stages (press Ctrl-C again to exit right away)");
+    cancellation.cancel();
+    if tokio::signal::ctrl_c().await.is_ok() {
+        std::process::exit(130);
+    }
+}
+
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     match run().await {
-        Ok(_) => {},
+        Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
             log::error!("{}", e);
+            ExitCode::from(e.exit_code())
         }
     }
 }
 
-async fn run() -> Result<(), String> {
+async fn run() -> Result<(), Error> {
     dotenv::dotenv().ok();
 
     let cli = Cli::parse();
@@ -59,37 +117,85 @@ async fn run() -> Result<(), String> {
     if let Some(schema_path) = cli.schema {
         log::info!("Generating schema...");
         let schema = schemars::schema_for!(schema::Workflows);
-        let schema_string = serde_json::to_string_pretty(&schema).map_err(|e| e.to_string())?;
-        let mut file = std::fs::File::create(schema_path).map_err(|e| e.to_string())?;
+        let schema_string = serde_json::to_string_pretty(&schema).map_err(|e| Error::RuntimeError(e.to_string()))?;
+        let mut file = std::fs::File::create(schema_path).map_err(|e| Error::RuntimeError(e.to_string()))?;
         log::info!("Writing schema...");
-        file.write_all(schema_string.as_bytes()).map_err(|e| e.to_string())?;
+        file.write_all(schema_string.as_bytes()).map_err(|e| Error::RuntimeError(e.to_string()))?;
         log::info!("Schema saved!");
         return Ok(());
     }
 
-    log::info!("Loading OpenAI token");
-    llm::load_token().map_err(|e| e.to_string())?;
-    log::info!("Loading workflow");
     let path = cli.workflows_file.unwrap_or("yc-workflows.yaml".to_string());
-    let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
-    let workflows: schema::Workflows = serde_yaml::from_reader(file).map_err(|e| e.to_string())?;
+    if let Some(Command::Validate) = cli.command {
+        let issues = validate::validate(std::path::Path::new(&path));
+        for issue in &issues {
+            println!("{}", issue);
+        }
+        if !issues.is_empty() {
+            return Err(Error::InvalidWorkflow(format!("Found {} problems in {}", issues.len(), path)));
+        }
+        log::info!("No problems found in {}", path);
+        return Ok(());
+    }
+
+    if let Some(Command::Diagram { workflow, format }) = &cli.command {
+        let engine = Engine::builder().workflows_file(&path).build()?;
+        let workflow = engine.workflow(workflow.as_deref().or(cli.name.as_deref()))?;
+        match format {
+            DiagramFormat::Mermaid => print!("{}", diagram::mermaid(workflow)),
+            DiagramFormat::Dot => print!("{}", diagram::dot(workflow)),
+        }
+        return Ok(());
+    }
+
+    // A dry run calls no models, so it works without a token
+    if !cli.dry_run {
+        log::info!("Loading OpenAI token");
+        llm::load_token()?;
+    }
+    log::info!("Loading workflow");
     let workdir = cli.workdir.unwrap_or(".".to_string());
     let workdir = std::path::Path::new(&workdir);
+    let builder = Engine::builder().workflows_file(&path).workdir(workdir);
+    let engine = match cli.interface {
+        Some(InterfaceSelection::Cli) => builder.interface(interface::cli::CliInterface::new()),
+        Some(InterfaceSelection::Vim) | None => builder.interface(interface::vim::VimInterface::new()),
+    }.build()?;
 
-    let workflow: &schema::Workflow = if let Some(workflow_name) = cli.name {
-        workflows.workflows.iter().find(|wf| wf.name == workflow_name)
-            .expect(&format!("No workflow found with the name: {}", workflow_name))
-    } else if workflows.workflows.len() == 1 {
-        workflows.workflows.first()
-            .expect("No workflows found")
-    } else {
-        panic!("No workflow name provided and there are multiple workflows in the file. Please provide the workflow name.")
+    // A resumed run already knows its workflow
+    let name = match (&cli.name, &cli.resume) {
+        (None, Some(id)) => Some(RunState::read(workdir, id)?.workflow),
+        (name, _) => name.clone(),
+    };
+    let dry_run = match cli.dry_run {
+        true => Some(DryRun::load(cli.dry_run_outputs.as_deref().map(std::path::Path::new))?),
+        false => None,
     };
-    let interface: Box<dyn Interface> = match cli.interface {
-        Some(InterfaceSelection::Cli) => Box::new(interface::cli::CliInterface::new()),
-        Some(InterfaceSelection::Vim) => Box::new(interface::vim::VimInterface::new()),
-        None => Box::new(interface::vim::VimInterface::new()),
+    // Variables given on the command line override the ones of a previous run
+    let mut variables = match &cli.vars_from {
+        Some(id) => RunState::read(workdir, id)?.variables,
+        None => HashMap::new(),
+    };
+    for (name, path) in cli.var_files {
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| Error::InvalidEnvironment(format!("Cannot read {} for variable {}: {}", path, name, e)))?;
+        variables.insert(name, StageOutput::Text(content));
+    }
+    for (name, value) in cli.vars {
+        variables.insert(name, StageOutput::Text(value));
+    }
+    let cancellation = Arc::new(Cancellation::default());
+    tokio::spawn(cancel_on_ctrl_c(cancellation.clone()));
+    let options = workflows::RunOptions {
+        resume: cli.resume,
+        dry_run,
+        from: cli.only.clone().or(cli.from),
+        until: cli.only.or(cli.until),
+        variables,
+        journal: cli.journal,
+        save_state: true,
+        cancellation,
     };
-    workflows::run_workflow(workflow, interface.as_ref(), &workdir).await.map_err(|e| e.to_string())?;
+    engine.run_with(name.as_deref(), &options).await?;
     Ok(())
 }
@@ -1,27 +1,441 @@
-use schemars::JsonSchema;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::{Deserialize, Serialize};
 
+use crate::error::Error;
+use crate::workflows::condition::Condition;
+use crate::workflows::graph;
+use crate::workflows::stages::for_each::ForEachSource;
 pub use crate::generated::WorkflowStage;
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct Workflows {
+    /// Files whose workflows, prompts and stage templates are available as `<namespace>.<name>`
+    #[serde(default)]
+    pub imports: Vec<Import>,
+    /// Texts available in every workflow as `${prompts.<name>}`
+    #[serde(default)]
+    pub prompts: BTreeMap<String, String>,
+    /// Stage definitions reused by stages with a `template`
+    #[serde(default)]
+    pub stage_templates: BTreeMap<String, StageTemplate>,
+    /// Executables run by `plugin` stages, by name
+    #[serde(default)]
+    pub plugins: BTreeMap<String, Plugin>,
     pub workflows: Vec<Workflow>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Import {
+    /// Path relative to the importing file
+    pub path: String,
+    /// Defaults to the file name without its extension
+    #[serde(rename = "as")]
+    pub namespace: Option<String>,
+}
+
+impl Import {
+    pub fn namespace(&self, path: &Path) -> String {
+        match &self.namespace {
+            Some(namespace) => namespace.clone(),
+            None => path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct StageTemplate {
+    pub description: Option<String>,
+    pub retry: Option<RetryPolicy>,
+    pub timeout: Option<Duration>,
+    pub stage: WorkflowStage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Plugin {
+    pub description: Option<String>,
+    /// Executable of the plugin, paths starting with `.` are relative to the file registering it
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Workflow {
     pub name: String,
     pub description: Option<String>,
+    #[serde(default)]
+    pub execution: ExecutionMode,
+    /// Default timeout for stages without their own, block stages are only limited by their inner stages
+    pub timeout: Option<Duration>,
+    /// Variables set before the first stage, passed by `call_workflow` or asked from the user
+    #[serde(default)]
+    pub inputs: Vec<WorkflowInput>,
+    /// Variables returned to `call_workflow`, all variables are returned when empty
+    #[serde(default)]
+    pub outputs: Vec<String>,
     pub stages: Vec<WorkflowStageData>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WorkflowInput {
+    pub name: String,
+    pub description: Option<String>,
+    /// Makes the input optional
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutionMode {
+    /// Runs stages one after another in the order they are defined
+    #[default]
+    Sequential,
+    /// Runs every stage as soon as the stages it references have finished.
+    /// Interactive stages still run one at a time, in the order they are defined.
+    Graph,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct WorkflowStageData {
     pub name: String,
     pub description: Option<String>,
+    pub retry: Option<RetryPolicy>,
+    /// Cancels the stage when it takes longer, applies to every retry attempt separately
+    pub timeout: Option<Duration>,
+    /// The stage is skipped when this condition is false
+    pub when: Option<Condition>,
+    /// Output of the stage when it is skipped, empty by default
+    pub default: Option<String>,
+    /// Name of a stage template that provides the fields not set here
+    pub template: Option<String>,
+    #[schemars(with = "Option<WorkflowStage>")]
     pub stage: WorkflowStage,
 }
 
+/// Runs the stage again when it fails or when its output does not pass validation
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub attempts: u32,
+    #[serde(default)]
+    pub backoff: Backoff,
+    /// Checked after every attempt, the output is available under the stage name
+    pub validate: Option<Condition>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Backoff {
+    /// Delay before the first retry, like `500ms` or `2s`
+    #[serde(default = "Backoff::default_delay")]
+    pub delay: Duration,
+    /// Every next delay is multiplied by this factor, at least 1
+    #[serde(default = "Backoff::default_multiplier")]
+    pub multiplier: f64,
+    pub max_delay: Option<Duration>,
+}
+
+impl Backoff {
+    fn default_delay() -> Duration {
+        Duration(std::time::Duration::from_secs(1))
+    }
+
+    fn default_multiplier() -> f64 {
+        2.0
+    }
+
+    pub fn delay(&self, retry: u32) -> std::time::Duration {
+        let max_delay = self.max_delay.map_or(std::time::Duration::MAX, |max_delay| max_delay.0);
+        // Computed in seconds, so a large retry count saturates at `max_delay` instead of overflowing
+        let secs = self.delay.0.as_secs_f64() * self.multiplier.powi(retry.min(i32::MAX as u32) as i32);
+        std::time::Duration::try_from_secs_f64(secs).unwrap_or(max_delay).min(max_delay)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: Self::default_delay(),
+            multiplier: Self::default_multiplier(),
+            max_delay: None,
+        }
+    }
+}
+
+/// A duration written like `30s`, `1m 30s` or `500ms`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Duration(pub std::time::Duration);
+
+impl TryFrom<String> for Duration {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        humantime::parse_duration(&value)
+            .map(Duration)
+            .map_err(|e| Error::InvalidWorkflow(format!("Invalid duration `{}`: {}", value, e)))
+    }
+}
+
+impl From<Duration> for String {
+    fn from(duration: Duration) -> Self {
+        humantime::format_duration(duration.0).to_string()
+    }
+}
+
+impl JsonSchema for Duration {
+    fn schema_name() -> String {
+        "Duration".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
+/// Copies a variable from the scope of a block stage into the scope that contains it.
+/// Either the name of a stage inside the block, or a template rendered in the block's scope.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Export {
+    Variable(String),
+    Template { name: String, value: String },
+}
+
+impl Export {
+    pub fn name(&self) -> &str {
+        match self {
+            Export::Variable(name) => name,
+            Export::Template { name, .. } => name,
+        }
+    }
+}
+
+// A nested scope of a block stage: its stage lists and the variables exported from it
+pub struct Block<'a> {
+    pub stages: Vec<&'a [WorkflowStageData]>,
+    pub exports: &'a [Export],
+}
+
+impl<'a> Block<'a> {
+    fn new(stages: Vec<&'a [WorkflowStageData]>, exports: &'a [Export]) -> Self {
+        Self { stages, exports }
+    }
+}
+
+impl WorkflowStage {
+    pub fn blocks(&self) -> Vec<Block<'_>> {
+        match self {
+            WorkflowStage::ForEach(info) => vec![Block::new(vec![&info.stages], &info.exports)],
+            WorkflowStage::IfElse(info) => vec![Block::new(vec![&info.if_stages, &info.else_stages], &info.exports)],
+            WorkflowStage::Until(info) => vec![Block::new(vec![&info.stages], &info.exports)],
+            WorkflowStage::Try(info) => {
+                let stages = std::iter::once(info.stages.as_slice())
+                    .chain(info.catch.iter().map(|clause| clause.stages.as_slice()))
+                    .chain(std::iter::once(info.finally.as_slice()))
+                    .collect();
+                vec![Block::new(stages, &info.exports)]
+            },
+            WorkflowStage::Switch(info) => {
+                let stages = info.cases.iter()
+                    .map(|case| case.stages.as_slice())
+                    .chain(std::iter::once(info.default.as_slice()))
+                    .collect();
+                vec![Block::new(stages, &info.exports)]
+            },
+            WorkflowStage::Parallel(info) => info.branches.iter()
+                .map(|branch| Block::new(vec![&branch.stages], &branch.exports))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    // The `type` of the stage in workflow files, like `for_each`
+    pub fn type_name(&self) -> String {
+        serde_json::to_value(self).ok()
+            .and_then(|value| value.get("type")?.as_str().map(String::from))
+            .unwrap_or_default()
+    }
+
+    // Stages that ask the user for input
+    pub fn waits_for_user(&self) -> bool {
+        matches!(self, WorkflowStage::UserInput(_) | WorkflowStage::Echo(_) | WorkflowStage::FeedbackLoop(_))
+    }
+
+    // `call_workflow` exports the outputs of the workflow as `<stage>.<output>`
+    pub fn exports_prefixed(&self) -> bool {
+        matches!(self, WorkflowStage::CallWorkflow(_))
+    }
+
+    pub fn nested_stages(&self) -> Vec<&[WorkflowStageData]> {
+        self.blocks().into_iter().flat_map(|block| block.stages).collect()
+    }
+
+    // Names that a block stage adds to the scope it runs in, besides its own name
+    pub fn exported_names(&self) -> Vec<&str> {
+        let branches: Vec<&str> = match self {
+            WorkflowStage::Parallel(info) => info.branches.iter().map(|branch| branch.name.as_str()).collect(),
+            _ => vec![],
+        };
+        self.blocks().into_iter()
+            .flat_map(|block| block.exports.iter().map(Export::name))
+            .chain(branches)
+            .collect()
+    }
+}
+
+impl WorkflowStageData {
+    // Names that this stage adds to the scope it runs in
+    pub fn defined_names(&self) -> Vec<&str> {
+        std::iter::once(self.name.as_str())
+            .chain(self.stage.exported_names())
+            .collect()
+    }
+}
+
+impl Workflows {
+    pub fn check(&self) -> Result<(), Error> {
+        let mut names = HashSet::new();
+        for workflow in &self.workflows {
+            if !names.insert(workflow.name.as_str()) {
+                return Err(Error::InvalidWorkflow(format!("Workflow {} is defined more than once", workflow.name)));
+            }
+            check_scope(&workflow.stages)?;
+            check_calls(self, &workflow.stages)?;
+            if workflow.execution == ExecutionMode::Graph {
+                graph::dependencies(&workflow.stages, self)?;
+            }
+            let defined: HashSet<&str> = workflow.stages.iter()
+                .flat_map(|stage| stage.defined_names())
+                .chain(workflow.inputs.iter().map(|input| input.name.as_str()))
+                .collect();
+            for output in &workflow.outputs {
+                if !defined.contains(output.as_str()) && !is_prefixed_export(&workflow.stages, output) {
+                    return Err(Error::InvalidWorkflow(format!("Workflow {} outputs {}, which is not defined in it", workflow.name, output)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_calls(workflows: &Workflows, stages: &[WorkflowStageData]) -> Result<(), Error> {
+    for stage in stages {
+        for inner in stage.stage.nested_stages() {
+            check_calls(workflows, inner)?;
+        }
+        let info = match &stage.stage {
+            WorkflowStage::CallWorkflow(info) => info,
+            _ => continue,
+        };
+        let callee = workflows.workflows.iter()
+            .find(|workflow| workflow.name == info.workflow)
+            .ok_or_else(|| Error::InvalidWorkflow(format!("Stage {} calls workflow {}, which does not exist", stage.name, info.workflow)))?;
+        for name in info.inputs.keys() {
+            if !callee.inputs.iter().any(|input| &input.name == name) {
+                return Err(Error::InvalidWorkflow(format!("Stage {} passes {}, which is not an input of workflow {}", stage.name, name, callee.name)));
+            }
+        }
+        for input in &callee.inputs {
+            if input.default.is_none() && !info.inputs.contains_key(&input.name) {
+                return Err(Error::InvalidWorkflow(format!("Stage {} does not pass input {} of workflow {}", stage.name, input.name, callee.name)));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether the name is `<stage>.<output>` of a stage that exports such names
+fn is_prefixed_export(stages: &[WorkflowStageData], name: &str) -> bool {
+    name.split_once('.')
+        .is_some_and(|(prefix, _)| stages.iter().any(|stage| stage.name == prefix && stage.stage.exports_prefixed()))
+}
+
+fn check_stage(stage: &WorkflowStageData) -> Result<(), Error> {
+    if stage.retry.as_ref().is_some_and(|retry| retry.attempts == 0) {
+        return Err(Error::InvalidWorkflow(format!("Stage {} needs at least 1 retry attempt", stage.name)));
+    }
+    if let Some(retry) = stage.retry.as_ref().filter(|retry| !(retry.backoff.multiplier.is_finite() && retry.backoff.multiplier >= 1.0)) {
+        return Err(Error::InvalidWorkflow(format!("Stage {} has an invalid backoff multiplier {}, it must be at least 1", stage.name, retry.backoff.multiplier)));
+    }
+    let valid = match &stage.stage {
+        WorkflowStage::IfElse(info) => info.condition.is_some() != (info.left.is_some() && info.right.is_some()),
+        WorkflowStage::Until(info) => info.condition.is_some() != (info.value.is_some() && info.expected_value.is_some()),
+        WorkflowStage::ForEach(info) => {
+            if info.parallelism == Some(0) {
+                return Err(Error::InvalidWorkflow(format!("Stage {} needs a parallelism of at least 1", stage.name)));
+            }
+            if matches!(&info.source, ForEachSource::Range(range) if range.step == 0) {
+                return Err(Error::InvalidWorkflow(format!("Stage {} has a range with a step of 0", stage.name)));
+            }
+            true
+        },
+        WorkflowStage::MapReduce(info) => {
+            if info.chunking.size == 0 || info.chunking.overlap >= info.chunking.size {
+                return Err(Error::InvalidWorkflow(format!("Stage {} needs a chunk size larger than its overlap", stage.name)));
+            }
+            if info.parallelism == Some(0) {
+                return Err(Error::InvalidWorkflow(format!("Stage {} needs a parallelism of at least 1", stage.name)));
+            }
+            true
+        },
+        WorkflowStage::Fail(info) => {
+            if info.exit_code == Some(0) {
+                return Err(Error::InvalidWorkflow(format!("Stage {} cannot fail with exit code 0", stage.name)));
+            }
+            true
+        },
+        WorkflowStage::Switch(info) => {
+            for pattern in info.patterns().filter(|pattern| !pattern.contains("${")) {
+                regex::Regex::new(pattern).map_err(|e| Error::InvalidWorkflow(format!("Stage {} has an invalid pattern: {}", stage.name, e)))?;
+            }
+            true
+        },
+        _ => true,
+    };
+    if !valid {
+        return Err(Error::InvalidWorkflow(format!("Stage {} needs either a condition or a pair of values to compare", stage.name)));
+    }
+    Ok(())
+}
+
+// Every variable exported by a block must be defined directly inside it,
+// either as a stage name or as an export of an inner block.
+fn check_scope(stages: &[WorkflowStageData]) -> Result<(), Error> {
+    for stage in stages {
+        check_stage(stage)?;
+        for inner in stage.stage.nested_stages() {
+            check_scope(inner).map_err(|e| e.at_stage(&stage.name))?;
+        }
+        let mut exported = HashSet::new();
+        for name in stage.stage.exported_names() {
+            if name == stage.name {
+                return Err(Error::InvalidWorkflow(format!("Stage {} cannot export its own name", stage.name)));
+            }
+            if !exported.insert(name) {
+                return Err(Error::InvalidWorkflow(format!("Stage {} exports {} more than once", stage.name, name)));
+            }
+        }
+        for block in stage.stage.blocks() {
+            let defined: HashSet<&str> = block.stages.iter()
+                .flat_map(|stages| stages.iter())
+                .flat_map(|s| s.defined_names())
+                .collect();
+            for export in block.exports {
+                if let Export::Variable(variable) = export {
+                    let prefixed = block.stages.iter().any(|stages| is_prefixed_export(stages, variable));
+                    if !defined.contains(variable.as_str()) && !prefixed {
+                        return Err(Error::InvalidWorkflow(format!("Stage {} exports {}, which is not defined inside it", stage.name, variable)));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub enum Model {
     #[serde(rename = "gpt-4")]
@@ -44,3 +458,103 @@ impl Model {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workflows(exports: &str) -> Workflows {
+        serde_yaml::from_str(&format!(r#"
+workflows:
+  - name: test
+    stages:
+      - name: condition
+        stage:
+          type: if_else
+          left: a
+          right: b
+          if_stages:
+            - name: first
+              stage:
+                type: set
+                value: one
+          else_stages:
+            - name: second
+              stage:
+                type: set
+                value: two
+          exports: {}
+"#, exports)).unwrap()
+    }
+
+    #[test]
+    fn test_exports_from_any_branch() {
+        assert!(workflows("[first, second, { name: both, value: '${first}' }]").check().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_exports() {
+        assert!(workflows("[third]").check().is_err());
+        assert!(workflows("[first, first]").check().is_err());
+        assert!(workflows("[{ name: condition, value: x }]").check().is_err());
+    }
+
+    #[test]
+    fn test_call_inputs() {
+        let workflows = |inputs: &str| -> Workflows {
+            serde_yaml::from_str(&format!(r#"
+workflows:
+  - name: main
+    stages:
+      - name: call
+        stage:
+          type: call_workflow
+          workflow: callee
+          inputs: {}
+  - name: callee
+    inputs:
+      - name: text
+      - name: style
+        default: short
+    stages: []
+"#, inputs)).unwrap()
+        };
+        assert!(workflows("{ text: a }").check().is_ok());
+        assert!(workflows("{ style: long }").check().is_err());
+        assert!(workflows("{ text: a, other: b }").check().is_err());
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = |yaml: &str| -> Backoff { serde_yaml::from_str(yaml).unwrap() };
+        let secs = std::time::Duration::from_secs;
+        let doubling = backoff("{ delay: 1s }");
+        assert_eq!(doubling.delay(0), secs(1));
+        assert_eq!(doubling.delay(3), secs(8));
+        assert_eq!(doubling.delay(u32::MAX), std::time::Duration::MAX);
+        let capped = backoff("{ delay: 1s, multiplier: 10, max_delay: 1m }");
+        assert_eq!(capped.delay(1), secs(10));
+        assert_eq!(capped.delay(2), secs(60));
+        assert_eq!(capped.delay(1000), secs(60));
+        assert_eq!(backoff("{ delay: 2s, multiplier: 1 }").delay(100), secs(2));
+    }
+
+    #[test]
+    fn test_invalid_backoff() {
+        let workflows = |multiplier: &str| -> Workflows {
+            serde_yaml::from_str(&format!(r#"
+workflows:
+  - name: test
+    stages:
+      - name: retried
+        retry: {{ attempts: 3, backoff: {{ multiplier: {} }} }}
+        stage: {{ type: set, value: a }}
+"#, multiplier)).unwrap()
+        };
+        assert!(workflows("1.5").check().is_ok());
+        assert!(workflows("0.5").check().is_err());
+        assert!(workflows("-2").check().is_err());
+        assert!(workflows(".nan").check().is_err());
+        assert!(workflows(".inf").check().is_err());
+    }
+}
@@ -0,0 +1,516 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display, path::{Path, PathBuf}};
+
+use regex::Regex;
+use serde_yaml::Value;
+
+use crate::{error::Error, loader::{self, Source}, schema::{Import, Model, Workflow, WorkflowStage, WorkflowStageData, Workflows}};
+use crate::workflows::{references::{references_in, StageTexts}, stages::for_each::ForEachSource};
+
+// A problem found in a workflows file, with its line when it can be found
+#[derive(Debug)]
+pub struct Issue {
+    pub path: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path, line, self.message),
+            None => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+// Checks a workflows file and everything it imports without running anything.
+// Problems that stop the file from loading are reported alone, otherwise all problems are reported.
+pub fn validate(path: &Path) -> Vec<Issue> {
+    let mut files = Files::default();
+    let mut issues = Vec::new();
+    files.read(path, &mut issues);
+    if !issues.is_empty() {
+        return issues;
+    }
+    issues.extend(files.unknown_models());
+    if !issues.is_empty() {
+        return issues;
+    }
+    let (workflows, sources) = match loader::load_unchecked(path) {
+        Ok(loaded) => loaded,
+        Err(e) => return vec![files.locate_error(path, &e.to_string())],
+    };
+    if let Err(e) = workflows.check() {
+        issues.push(files.locate_check_error(&workflows, &sources, path, &e));
+    }
+    for workflow in &workflows.workflows {
+        let source = match sources.get(&workflow.name) {
+            Some(source) => source,
+            None => continue,
+        };
+        let mut linter = Linter { workflows: &workflows, workflow, source, files: &files, issues: &mut issues, seen: HashMap::new() };
+        linter.lint();
+    }
+    issues
+}
+
+#[derive(Default)]
+struct Files {
+    // Path as written in the imports and content, by canonical path
+    files: HashMap<PathBuf, (String, String)>,
+}
+
+impl Files {
+    fn read(&mut self, path: &Path, issues: &mut Vec<Issue>) {
+        let display = path.display().to_string();
+        let canonical = match path.canonicalize() {
+            Ok(canonical) => canonical,
+            Err(e) => return issues.push(Issue { path: display, line: None, message: format!("Cannot open file: {}", e) }),
+        };
+        // Import cycles are reported by the loader
+        if self.files.contains_key(&canonical) {
+            return;
+        }
+        let content = match std::fs::read_to_string(&canonical) {
+            Ok(content) => content,
+            Err(e) => return issues.push(Issue { path: display, line: None, message: format!("Cannot read file: {}", e) }),
+        };
+        let value: Value = match serde_yaml::from_str(&content) {
+            Ok(value) => value,
+            Err(e) => return issues.push(Issue { path: display, line: e.location().map(|location| location.line()), message: e.to_string() }),
+        };
+        self.files.insert(canonical, (display, content));
+        let imports: Vec<Import> = value.get("imports")
+            .and_then(|imports| serde_yaml::from_value(imports.clone()).ok())
+            .unwrap_or_default();
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for import in imports {
+            self.read(&dir.join(&import.path), issues);
+        }
+    }
+
+    // Models are checked on the stages of every file, before the files are merged, so that the line can be found
+    fn unknown_models(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        for (display, content) in self.files.values() {
+            let value: Value = match serde_yaml::from_str(content) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            let mut models = Vec::new();
+            stage_models(&value, &mut models);
+            let mut seen: HashMap<&str, usize> = HashMap::new();
+            for model in models {
+                let occurrence = seen.entry(model).or_insert(0);
+                *occurrence += 1;
+                if let Err(e) = serde_yaml::from_value::<Model>(Value::String(model.to_string())) {
+                    let re = Regex::new(&format!(r#"(^|[\s{{,-])model:\s*["']?{}["']?\s*($|[,}}#])"#, regex::escape(model))).unwrap();
+                    let line = content.lines().enumerate()
+                        .filter(|(_, line)| re.is_match(line))
+                        .nth(*occurrence - 1)
+                        .map(|(i, _)| i + 1);
+                    issues.push(Issue { path: display.clone(), line, message: format!("Unknown model: {}", e) });
+                }
+            }
+        }
+        issues
+    }
+
+    // Errors from deserializing the merged files have no location, the same error from one of the files has
+    fn locate_error(&self, path: &Path, message: &str) -> Issue {
+        // Only errors from a file start with the path of the field, like `workflows[0].stages[1]: `
+        let field_path = Regex::new(r"^[\w\[\]\.]+: ").unwrap();
+        for (display, content) in self.files.values() {
+            if let Err(e) = serde_yaml::from_str::<Workflows>(content) {
+                if let Some(location) = e.location() {
+                    let located = e.to_string().replace(&format!(" at line {} column {}", location.line(), location.column()), "");
+                    let without_path = field_path.replace(&located, "");
+                    if message.contains(without_path.as_ref()) {
+                        return Issue { path: display.clone(), line: Some(location.line()), message: located };
+                    }
+                }
+            }
+        }
+        Issue { path: path.display().to_string(), line: None, message: message.to_string() }
+    }
+
+    // Errors from checking the workflows start with the stage or workflow they are about, like `Stage x cannot...`
+    fn locate_check_error(&self, workflows: &Workflows, sources: &HashMap<String, Source>, path: &Path, error: &Error) -> Issue {
+        let message = error.to_string();
+        let subject = match error.root() {
+            Error::InvalidWorkflow(root) => Regex::new(r"^(Stage |Workflow )?(\S+) ").unwrap().captures(root)
+                .map(|caps| (caps.get(1).is_some_and(|kind| kind.as_str() == "Workflow "), caps[2].to_string())),
+            _ => None,
+        };
+        let issue = match subject {
+            Some((true, workflow)) => sources.get(&workflow).map(|source| self.issue(source, None, 0, message.clone())),
+            // Stage names are only unique in a workflow, so the line is looked up in the workflow that has the stage
+            // inside the block stages the error passed through
+            Some((false, stage)) => workflows.workflows.iter()
+                .filter(|workflow| has_stage(&workflow.stages, &error.stage_path(), &stage))
+                .find_map(|workflow| sources.get(&workflow.name))
+                .map(|source| self.issue(source, Some(&stage), 0, message.clone())),
+            None => None,
+        };
+        issue.unwrap_or_else(|| Issue { path: path.display().to_string(), line: None, message })
+    }
+
+    fn lines(&self, source: &Source, name: &str) -> Vec<usize> {
+        let content = match self.files.get(&source.path) {
+            Some((_, content)) => content,
+            None => return vec![],
+        };
+        let re = Regex::new(&format!(r#"(^|[\s{{,-])name:\s*["']?{}["']?\s*($|[,}}#])"#, regex::escape(name))).unwrap();
+        content.lines().enumerate()
+            .filter(|(_, line)| re.is_match(line))
+            .map(|(i, _)| i + 1)
+            .collect()
+    }
+
+    // Line of the nth stage with the name after the start of the workflow
+    fn stage_line(&self, source: &Source, stage: &str, occurrence: usize) -> Option<usize> {
+        let start = self.lines(source, &source.name).first().copied()?;
+        self.lines(source, stage).into_iter()
+            .filter(|line| *line > start)
+            .nth(occurrence)
+    }
+
+    fn issue(&self, source: &Source, stage: Option<&str>, occurrence: usize, message: String) -> Issue {
+        let path = self.files.get(&source.path)
+            .map(|(display, _)| display.clone())
+            .unwrap_or_else(|| source.path.display().to_string());
+        let line = match stage {
+            Some(stage) => self.stage_line(source, stage, occurrence),
+            None => self.lines(source, &source.name).first().copied(),
+        };
+        Issue { path, line, message }
+    }
+}
+
+// Names visible to a stage
+#[derive(Clone, Default)]
+struct Scope {
+    names: HashSet<String>,
+    // `call_workflow` stages, whose outputs are `<stage>.<output>`
+    prefixed: HashSet<String>,
+    // Names that hold a text and never a list, with what defines them
+    texts: HashMap<String, String>,
+}
+
+impl Scope {
+    fn contains(&self, name: &str) -> bool {
+        self.names.contains(name) || name.split_once('.').is_some_and(|(prefix, _)| self.prefixed.contains(prefix))
+    }
+
+    fn define(&mut self, stage: &WorkflowStageData) {
+        for name in stage.defined_names() {
+            self.names.insert(name.to_string());
+            self.texts.remove(name);
+        }
+        if stage.stage.exports_prefixed() {
+            self.prefixed.insert(stage.name.clone());
+        }
+        if !can_output_list(&stage.stage) {
+            self.texts.insert(stage.name.clone(), format!("a {} stage", stage.stage.type_name()));
+        }
+    }
+}
+
+struct Linter<'a> {
+    workflows: &'a Workflows,
+    workflow: &'a Workflow,
+    source: &'a Source,
+    files: &'a Files,
+    issues: &'a mut Vec<Issue>,
+    // How many stages with each name were checked, to find the line of stages sharing a name
+    seen: HashMap<&'a str, usize>,
+}
+
+impl<'a> Linter<'a> {
+    fn lint(&mut self) {
+        let mut scope = Scope::default();
+        for input in &self.workflow.inputs {
+            scope.names.insert(input.name.clone());
+            scope.texts.insert(input.name.clone(), "an input".to_string());
+        }
+        for name in self.workflows.prompts.keys() {
+            scope.names.insert(format!("prompts.{}", name));
+        }
+        self.lint_stages(&self.workflow.stages, &scope);
+    }
+
+    fn report(&mut self, stage: &WorkflowStageData, message: String) {
+        let occurrence = self.seen.get(stage.name.as_str()).map_or(0, |seen| seen - 1);
+        let message = format!("{} > {}: {}", self.workflow.name, stage.name, message);
+        self.issues.push(self.files.issue(self.source, Some(&stage.name), occurrence, message));
+    }
+
+    fn lint_stages(&mut self, stages: &'a [WorkflowStageData], outer: &Scope) {
+        let mut scope = outer.clone();
+        let mut failed: Option<&str> = None;
+        let mut names = HashSet::new();
+        for stage in stages {
+            *self.seen.entry(&stage.name).or_insert(0) += 1;
+            // Stages in different branches can share a name, as only one of them defines it
+            if !names.insert(stage.name.as_str()) {
+                self.report(stage, "Stage name is used by an earlier stage in the same list".to_string());
+            }
+            if let Some(failed) = failed.take() {
+                self.report(stage, format!("Stage can never run, stage {} before it always fails", failed));
+            }
+            self.lint_stage(stage, &scope);
+            if matches!(stage.stage, WorkflowStage::Fail(_)) && stage.when.is_none() {
+                failed = Some(&stage.name);
+            }
+            scope.define(stage);
+        }
+    }
+
+    fn lint_stage(&mut self, stage: &'a WorkflowStageData, scope: &Scope) {
+        // Fields of block stages can use the names defined inside them, like the condition of `until`
+        let mut own = scope.clone();
+        for inner in stage.stage.nested_stages() {
+            own.names.extend(all_names(inner));
+        }
+        let mut inner = scope.clone();
+        for name in local_names(&stage.stage) {
+            own.names.insert(name.clone());
+            inner.names.insert(name);
+        }
+
+        let mut references = stage_references(stage);
+        if let Some(validate) = stage.retry.as_ref().and_then(|retry| retry.validate.as_ref()) {
+            references.extend(references_in(validate.source()).into_iter().filter(|name| name != &stage.name));
+        }
+        for name in references {
+            if !own.contains(&name) {
+                self.report(stage, self.undefined(&name));
+            }
+        }
+
+        if let WorkflowStage::ForEach(info) = &stage.stage {
+            if let ForEachSource::List(list) = &info.source {
+                if !scope.contains(list) {
+                    self.report(stage, format!("for_each lists {}: {}", list, self.undefined(list)));
+                } else if let Some(text) = scope.texts.get(list) {
+                    self.report(stage, format!("for_each lists {}, which is {} and never a list", list, text));
+                }
+            }
+        }
+        for message in empty_blocks(&stage.stage) {
+            self.report(stage, message);
+        }
+
+        for stages in stage.stage.nested_stages() {
+            self.lint_stages(stages, &inner);
+        }
+    }
+
+    fn undefined(&self, name: &str) -> String {
+        let defined = self.workflow.stages.iter().any(|stage| all_names(std::slice::from_ref(stage)).any(|defined| defined == name));
+        match defined {
+            true => format!("${{{}}} is not defined before this stage", name),
+            false => format!("${{{}}} is not defined", name),
+        }
+    }
+}
+
+// Models set in the `stage` of stages and stage templates, in the order they are written
+fn stage_models<'v>(value: &'v Value, models: &mut Vec<&'v str>) {
+    match value {
+        Value::Mapping(mapping) => {
+            if let Some(model) = value.get("stage").and_then(|stage| stage.get("model")).and_then(Value::as_str) {
+                models.push(model);
+            }
+            mapping.iter().for_each(|(_, value)| stage_models(value, models));
+        },
+        Value::Sequence(values) => values.iter().for_each(|value| stage_models(value, models)),
+        _ => {},
+    }
+}
+
+// Whether a stage with the name is inside the stages on the path, at any depth
+fn has_stage(stages: &[WorkflowStageData], path: &[&str], name: &str) -> bool {
+    match path.split_first() {
+        Some((first, rest)) => stages.iter()
+            .filter(|stage| stage.name == *first)
+            .any(|stage| stage.stage.nested_stages().into_iter().any(|inner| has_stage(inner, rest, name))),
+        None => stages.iter()
+            .any(|stage| stage.name == name || stage.stage.nested_stages().into_iter().any(|inner| has_stage(inner, &[], name))),
+    }
+}
+
+// Names defined by stages and everything inside them
+fn all_names(stages: &[WorkflowStageData]) -> impl Iterator<Item = String> + '_ {
+    stages.iter().flat_map(|stage| {
+        let inner: Vec<String> = stage.stage.nested_stages().into_iter().flat_map(all_names).collect();
+        stage.defined_names().into_iter().map(String::from).chain(inner)
+    })
+}
+
+// Names a block stage sets for the stages inside it, or uses in its own fields
+fn local_names(stage: &WorkflowStage) -> Vec<String> {
+    let names: Vec<&str> = match stage {
+        WorkflowStage::ForEach(info) => vec![&info.variable, "loop.index", "loop.first", "loop.last"],
+        WorkflowStage::Try(_) => vec!["error.message", "error.kind", "error.stage"],
+        WorkflowStage::MapReduce(_) => vec!["chunk", "chunk.index", "chunk.count", "results"],
+        _ => vec![],
+    };
+    names.into_iter().map(String::from).collect()
+}
+
+fn empty_blocks(stage: &WorkflowStage) -> Vec<String> {
+    let mut messages = Vec::new();
+    match stage {
+        WorkflowStage::ForEach(info) if info.stages.is_empty() => messages.push("for_each has no stages".to_string()),
+        WorkflowStage::Until(info) if info.stages.is_empty() => messages.push("until has no stages".to_string()),
+        WorkflowStage::Try(info) if info.stages.is_empty() => messages.push("try has no stages".to_string()),
+        WorkflowStage::IfElse(info) if info.if_stages.is_empty() => messages.push("if_else has no if_stages".to_string()),
+        WorkflowStage::Switch(info) => {
+            if info.cases.is_empty() {
+                messages.push("switch has no cases".to_string());
+            }
+            for (i, _) in info.cases.iter().enumerate().filter(|(_, case)| case.stages.is_empty()) {
+                messages.push(format!("case {} of switch has no stages", i + 1));
+            }
+        },
+        WorkflowStage::Parallel(info) => {
+            if info.branches.is_empty() {
+                messages.push("parallel has no branches".to_string());
+            }
+            for branch in info.branches.iter().filter(|branch| branch.stages.is_empty()) {
+                messages.push(format!("branch {} has no stages", branch.name));
+            }
+        },
+        _ => {},
+    }
+    messages
+}
+
+// Stages that only output texts or nothing cannot be listed by `for_each`
+fn can_output_list(stage: &WorkflowStage) -> bool {
+    !matches!(stage,
+        WorkflowStage::AiProcessing(_) | WorkflowStage::ToJson(_) | WorkflowStage::ShellCommand(_) | WorkflowStage::ShellScript(_)
+        | WorkflowStage::PythonScript(_) | WorkflowStage::UserInput(_) | WorkflowStage::Set(_) | WorkflowStage::Echo(_)
+        | WorkflowStage::Print(_) | WorkflowStage::LogWarn(_) | WorkflowStage::SaveFile(_) | WorkflowStage::MapReduce(_)
+        | WorkflowStage::FeedbackLoop(_) | WorkflowStage::Fail(_) | WorkflowStage::Parallel(_))
+}
+
+// Names a stage references with `${name}` in its own fields, `when` and `default`, but not in the stages
+// nested in it or in Jinja templates
+pub fn stage_references(stage: &WorkflowStageData) -> Vec<String> {
+    let mut names: Vec<String> = StageTexts::of(stage, false).strings.iter().flat_map(|s| references_in(s)).collect();
+    // Variables sent to a plugin by name
+    if let WorkflowStage::Plugin(info) = &stage.stage {
+        names.extend(info.variables.iter().flatten().cloned());
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("workflows.yaml");
+        std::fs::write(&path, r#"
+workflows:
+  - name: test
+    inputs: [{ name: topic }]
+    stages:
+      - { name: early, stage: { type: set, value: "${late}" } }
+      - { name: late, stage: { type: set, value: "${topic} ${missing}" } }
+      - name: loop
+        stage:
+          type: for_each
+          list: topic
+          variable: item
+          stages:
+            - { name: inner, stage: { type: set, value: "${item} ${loop.index}" } }
+      - { name: stop, stage: { type: fail, message: "stop" } }
+      - { name: late, stage: { type: set, value: "x" } }
+"#).unwrap();
+        let issues = validate(&path);
+        let found: Vec<(Option<usize>, &str)> = issues.iter().map(|issue| (issue.line, issue.message.as_str())).collect();
+        assert_eq!(found, vec![
+            (Some(6), "test > early: ${late} is not defined before this stage"),
+            (Some(7), "test > late: ${missing} is not defined"),
+            (Some(8), "test > loop: for_each lists topic, which is an input and never a list"),
+            (Some(16), "test > late: Stage name is used by an earlier stage in the same list"),
+            (Some(16), "test > late: Stage can never run, stage stop before it always fails"),
+        ]);
+    }
+
+    #[test]
+    fn test_check_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("workflows.yaml");
+        let check = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            let issues = validate(&path);
+            assert_eq!(issues.len(), 1, "{:?}", issues);
+            (issues[0].line, issues[0].message.clone())
+        };
+        let (line, message) = check(r#"
+workflows:
+  - name: other
+    stages:
+      - { name: stop, stage: { type: set, value: "x" } }
+  - name: test
+    stages:
+      - name: block
+        stage:
+          type: try
+          stages:
+            - { name: stop, stage: { type: fail, message: "stop", exit_code: 0 } }
+"#);
+        assert_eq!(line, Some(12));
+        assert!(message.contains("Stage stop cannot fail with exit code 0"), "{}", message);
+
+        let (line, message) = check(r#"
+workflows:
+  - name: test
+    outputs: [missing]
+    stages:
+      - { name: a, stage: { type: set, value: "x" } }
+"#);
+        assert_eq!(line, Some(3));
+        assert!(message.contains("Workflow test outputs missing"), "{}", message);
+    }
+
+    #[test]
+    fn test_unknown_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("workflows.yaml");
+        std::fs::write(&path, "workflows:\n  - name: test\n    stages:\n      - { name: a, stage: { type: ai_processing, model: gpt-5, system_message: s, prompt: p } }\n").unwrap();
+        let issues = validate(&path);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].line, Some(4));
+        assert!(issues[0].message.contains("gpt-5"));
+    }
+
+    #[test]
+    fn test_models_outside_stages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("workflows.yaml");
+        std::fs::write(&path, r#"
+prompts:
+  notes: |
+    model: whatever
+workflows:
+  - name: test
+    stages:
+      # model: old-model
+      - { name: a, stage: { type: ai_processing, model: gpt-4, system_message: "model: gpt-5", prompt: p } }
+      - { name: b, stage: { type: ai_processing, model: gpt-5, system_message: s, prompt: p } }
+"#).unwrap();
+        let issues = validate(&path);
+        assert_eq!(issues.len(), 1, "{:?}", issues);
+        assert_eq!(issues[0].line, Some(10));
+        assert!(issues[0].message.contains("gpt-5"));
+    }
+}
@@ -1,15 +1,92 @@
-use std::{collections::HashMap, path::Path};
-use crate::{error::Error, schema::Workflow, interface::Interface};
+use std::{collections::HashMap, path::Path, sync::{Arc, Mutex}, time::Instant};
+use crate::{error::Error, schema::{ExecutionMode, Export, Workflow, WorkflowStageData, Workflows}, interface::Interface, llm::{LlmProvider, Message, Response}};
+use cancel::Cancellation;
+use checkpoint::RunState;
+use dry_run::DryRun;
+use journal::{Event, Journal};
 use stages::StageOutput;
-use regex::Regex;
+use template::TemplateEngine;
 
+pub mod cancel;
+pub mod checkpoint;
+pub mod condition;
+pub mod dry_run;
+pub mod graph;
+pub mod journal;
+pub mod process;
+pub mod references;
 pub mod stages;
+pub mod template;
+pub mod wrapper;
 
 #[derive(Debug)]
 pub struct Context<'a> {
     pub variables: &'a HashMap<String, StageOutput>,
     pub interface: &'a dyn Interface,
+    pub llm: &'a dyn LlmProvider,
     pub workdir: &'a std::path::Path,
+    pub exports: &'a Exports,
+    pub default_timeout: Option<std::time::Duration>,
+    pub workflows: &'a Workflows,
+    // Name of the stage that is running, empty outside of stages
+    pub stage_name: &'a str,
+    // Names of the stages the current stage runs in, followed by its own name
+    pub stage_path: &'a [String],
+    // Number of `call_workflow` stages the current stage runs in
+    pub depth: usize,
+    pub summary: &'a RunSummary,
+    // Set when stages with side effects only show what they would do
+    pub dry_run: Option<&'a DryRun>,
+    pub journal: Option<&'a Journal>,
+    pub cancellation: &'a Cancellation,
+}
+
+#[derive(Debug, Default)]
+pub struct Exports(Mutex<Vec<(String, StageOutput)>>);
+
+impl Exports {
+    fn take(&self) -> Vec<(String, StageOutput)> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+// Counts the stages of a run, including the ones in called workflows
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    finished: Mutex<usize>,
+    skipped: Mutex<Vec<String>>,
+}
+
+impl RunSummary {
+    pub fn finished(&self) {
+        *self.finished.lock().unwrap() += 1;
+    }
+
+    pub fn skipped(&self, stage_name: &str) {
+        self.skipped.lock().unwrap().push(stage_name.to_string());
+    }
+}
+
+impl std::fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let skipped = self.skipped.lock().unwrap();
+        write!(f, "{} stages finished, {} skipped", self.finished.lock().unwrap(), skipped.len())?;
+        if !skipped.is_empty() {
+            // Stages in loops can be skipped several times
+            let mut counts: Vec<(&str, usize)> = vec![];
+            for name in skipped.iter() {
+                match counts.iter_mut().find(|(counted, _)| counted == name) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((name, 1)),
+                }
+            }
+            let names: Vec<String> = counts.into_iter()
+                .map(|(name, count)| if count > 1 { format!("{} ({}x)", name, count) } else { name.to_string() })
+                .collect();
+            write!(f, " ({})", names.join(", "))?;
+        }
+        Ok(())
+    }
 }
 
 impl<'a> Context<'a> {
@@ -18,25 +95,105 @@ impl<'a> Context<'a> {
         Context {
             variables,
             interface: self.interface,
+            llm: self.llm,
             workdir: self.workdir,
+            exports: self.exports,
+            default_timeout: self.default_timeout,
+            workflows: self.workflows,
+            stage_name: self.stage_name,
+            stage_path: self.stage_path,
+            depth: self.depth,
+            summary: self.summary,
+            dry_run: self.dry_run,
+            journal: self.journal,
+            cancellation: self.cancellation,
         }
     }
 
+    fn scoped<'b>(&'a self, variables: &'b HashMap<String, StageOutput>, exports: &'b Exports) -> Context<'b> where 'a: 'b {
+        Context {
+            exports,
+            ..self.derive(variables)
+        }
+    }
+
+    // Makes a variable visible in the scope that contains the current stage
+    pub fn export<S: Into<String>>(&self, name: S, value: StageOutput) {
+        self.exports.0.lock().unwrap().push((name.into(), value));
+    }
+
+    pub fn export_all(&self, values: Vec<(String, StageOutput)>) {
+        self.exports.0.lock().unwrap().extend(values);
+    }
+
+    pub fn resolve_exports(&self, exports: &[Export], scope: &HashMap<String, StageOutput>) -> Result<Vec<(String, StageOutput)>, Error> {
+        let scope_ctx = self.derive(scope);
+        exports.iter()
+            .map(|export| match export {
+                Export::Variable(name) => {
+                    let value = scope.get(name).cloned().unwrap_or(StageOutput::None);
+                    Ok((name.clone(), value))
+                },
+                Export::Template { name, value } => {
+                    Ok((name.clone(), StageOutput::Text(scope_ctx.interpolate(value)?)))
+                },
+            })
+            .collect()
+    }
+
     pub fn interpolate<S: Into<String>>(&self, s: S) -> Result<String, Error> {
         let s = s.into();
-        let re = Regex::new(r"\$\{([^}]+)\}").unwrap();
-        
-        let result = re.replace_all(&s, |caps: &regex::Captures| {
-            let var_name = &caps[1];
-            match self.variables.get(var_name) {
-                Some(StageOutput::Text(value)) => value.to_string(),
-                Some(StageOutput::List(vec)) => vec.join("\n"),
-                Some(StageOutput::None) => return "".to_string(),
-                None => panic!("Variable not found: {}", var_name),
+        let re = references::reference_regex();
+
+        let mut result = String::new();
+        let mut end = 0;
+        for caps in re.captures_iter(&s) {
+            let reference = caps.get(0).unwrap();
+            result.push_str(&s[end..reference.start()]);
+            match self.get_variable(&caps[1])? {
+                StageOutput::Text(value) => result.push_str(value),
+                StageOutput::List(vec) => result.push_str(&vec.join("\n")),
+                StageOutput::None => {},
             }
-        });
+            end = reference.end();
+        }
+        result.push_str(&s[end..]);
+
+        if s.contains("${") {
+            self.record(Event::Input { path: self.stage_path, template: &s, value: &result });
+        }
+        Ok(result)
+    }
+
+    pub fn render<S: Into<String>>(&self, s: S, engine: TemplateEngine) -> Result<String, Error> {
+        match engine {
+            TemplateEngine::Interpolation => self.interpolate(s),
+            TemplateEngine::Jinja => {
+                let s = s.into();
+                let result = template::render_jinja(&s, self.variables, self.workdir)?;
+                self.record(Event::Input { path: self.stage_path, template: &s, value: &result });
+                Ok(result)
+            },
+        }
+    }
+
+    pub fn record(&self, event: Event) {
+        if let Some(journal) = self.journal {
+            journal.record(event);
+        }
+    }
 
-        Ok(result.to_string())
+    // Calls the model, recording the request and the response in the journal
+    pub async fn call_llm(&self, messages: Vec<Message>, model: &str) -> Result<Response, Error> {
+        self.record(Event::LlmRequest { path: self.stage_path, model, messages: &messages });
+        let start = Instant::now();
+        let result = self.llm.complete(messages, model).await;
+        let duration_ms = start.elapsed().as_millis();
+        match &result {
+            Ok(response) => self.record(Event::LlmResponse { path: self.stage_path, duration_ms, text: &response.text }),
+            Err(e) => self.record(Event::LlmFailed { path: self.stage_path, duration_ms, error: e.to_string() }),
+        }
+        result
     }
     
     pub fn get_variable(&self, var_name: &str) -> Result<&StageOutput, Error> {
@@ -44,18 +201,328 @@ impl<'a> Context<'a> {
     }
 }
 
-pub async fn run_workflow(workflow: &Workflow, interface: &'_ dyn Interface, workdir: &Path) -> Result<HashMap<String, StageOutput>, Error> {
-    let mut variables = HashMap::new();
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    // Id of a saved run to continue from its first unfinished stage
+    pub resume: Option<String>,
+    pub dry_run: Option<DryRun>,
+    // Names of the first and last top-level stages to run, all stages run by default
+    pub from: Option<String>,
+    pub until: Option<String>,
+    // Variables given before the run starts, in place of inputs and the outputs of stages that do not run
+    pub variables: HashMap<String, StageOutput>,
+    // Writes the events of the run to a JSON Lines file
+    pub journal: bool,
+    // Saves the variables after every top-level stage, so that a failed run can be resumed
+    pub save_state: bool,
+    // Cancelled when the user presses Ctrl-C
+    pub cancellation: Arc<Cancellation>,
+}
 
-    log::info!("Running workflow {}", workflow.name);
-    for stage in &workflow.stages {
-        log::info!("Running stage {}", stage.name);
-        let runner = stages::get_runner(&stage);
-        let output = runner.run(&Context { variables: &variables, interface, workdir }).await?;
-        log::info!("Stage {} finished", stage.name);
-        log::debug!("Stage {} output: {:?}", stage.name, output);
-        variables.insert(stage.name.clone(), output);
+impl RunOptions {
+    fn is_partial(&self) -> bool {
+        self.from.is_some() || self.until.is_some()
+    }
+}
+
+pub async fn run_workflow(workflows: &Workflows, workflow: &Workflow, interface: &'_ dyn Interface, llm: &'_ dyn LlmProvider, workdir: &Path, options: &RunOptions) -> Result<HashMap<String, StageOutput>, Error> {
+    let selected = select_stages(workflow, options)?;
+    let mut state = match &options.resume {
+        Some(id) => {
+            let state = RunState::resume(workdir, id, workflows, workflow)?;
+            log::info!("Resuming run {} after {} finished stages", id, state.completed.len());
+            state
+        },
+        None => {
+            let mut inputs = options.variables.clone();
+            // A partial run only asks for the inputs its stages use
+            let used: Vec<&str> = selected.stages.iter()
+                .flat_map(|stage| graph::outer_references(stage, workflow.inputs.iter().map(|input| input.name.as_str())))
+                .collect();
+            let asked = workflow.inputs.iter()
+                .filter(|input| !options.variables.contains_key(&input.name))
+                .filter(|input| !options.is_partial() || used.contains(&input.name.as_str()));
+            for input in asked {
+                let value = match &input.default {
+                    Some(default) => default.clone(),
+                    None => options.cancellation.guard(interface.get_input(format!("Value for input {}", input.name))).await?,
+                };
+                inputs.insert(input.name.clone(), StageOutput::Text(value));
+            }
+            RunState::new(workdir, workflows, workflow, inputs)
+        },
+    };
+    // A dry run does not save its state, as its outputs are not real, and neither does a partial run
+    let saved = options.save_state && options.dry_run.is_none() && !options.is_partial();
+    if saved && options.resume.is_none() {
+        state.reserve(workdir)?;
     }
+    let variables = state.variables.clone();
+    check_given(workflow, &selected, &variables)?;
 
+    let journal = match options.journal {
+        true => Some(Journal::open(workdir, &state.id)?),
+        false => None,
+    };
+    let root_variables = HashMap::new();
+    let exports = Exports::default();
+    let summary = RunSummary::default();
+    let ctx = Context {
+        variables: &root_variables,
+        interface,
+        llm,
+        workdir,
+        exports: &exports,
+        default_timeout: None,
+        workflows,
+        stage_name: "",
+        stage_path: &[],
+        depth: 0,
+        summary: &summary,
+        dry_run: options.dry_run.as_ref(),
+        journal: journal.as_ref(),
+        cancellation: &options.cancellation,
+    };
+    ctx.record(Event::RunStarted { run: &state.id, workflow: &workflow.name, resumed: options.resume.is_some() });
+    let result = execute(&selected, &ctx, variables, Some(&mut state).filter(|_| saved)).await;
+    ctx.record(Event::RunFinished {
+        run: &state.id,
+        summary: summary.to_string(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+    });
+    match &result {
+        Ok(_) => {
+            log::info!("Workflow {} finished: {}", workflow.name, summary);
+            if saved {
+                state.finish()?;
+            }
+        },
+        Err(e) => {
+            match e.is_interrupted() {
+                true => log::warn!("Workflow {} interrupted: {}", workflow.name, summary),
+                false => log::info!("Workflow {} failed: {}", workflow.name, summary),
+            }
+            if saved {
+                log::info!("Continue the run with --resume {}", state.id);
+            }
+        },
+    }
+    let (_, variables) = result?;
     Ok(variables)
 }
+
+// Keeps the top-level stages from `options.from` to `options.until`
+fn select_stages(workflow: &Workflow, options: &RunOptions) -> Result<Workflow, Error> {
+    if !options.is_partial() {
+        return Ok(workflow.clone());
+    }
+    let position = |name: &str| workflow.stages.iter().position(|stage| stage.name == name)
+        .ok_or_else(|| Error::InvalidWorkflow(format!("Workflow {} has no top-level stage {}", workflow.name, name)));
+    let start = options.from.as_deref().map(position).transpose()?.unwrap_or(0);
+    let end = match options.until.as_deref() {
+        Some(name) => position(name)?,
+        None => workflow.stages.len().saturating_sub(1),
+    };
+    if start > end {
+        return Err(Error::InvalidWorkflow(format!("Stage {} comes after stage {}", workflow.stages[start].name, workflow.stages[end].name)));
+    }
+    log::info!("Running stages {} to {} of workflow {}", workflow.stages[start].name, workflow.stages[end].name, workflow.name);
+    Ok(Workflow {
+        stages: workflow.stages[start..=end].to_vec(),
+        ..workflow.clone()
+    })
+}
+
+// Checks that the selected stages get the inputs and the outputs of left out stages they use
+fn check_given(workflow: &Workflow, selected: &Workflow, variables: &HashMap<String, StageOutput>) -> Result<(), Error> {
+    let left_out: Vec<&str> = workflow.stages.iter()
+        .filter(|stage| !selected.stages.iter().any(|s| s.name == stage.name))
+        .flat_map(|stage| stage.defined_names())
+        .chain(workflow.inputs.iter().map(|input| input.name.as_str()))
+        .collect();
+    let given = |name: &str| variables.contains_key(name) || variables.keys().any(|key| key.starts_with(&format!("{}.", name)));
+    let mut missing: Vec<&str> = selected.stages.iter()
+        .flat_map(|stage| graph::outer_references(stage, left_out.iter().copied()))
+        .filter(|name| !given(name))
+        .collect();
+    missing.sort();
+    missing.dedup();
+    if !missing.is_empty() {
+        return Err(Error::InvalidEnvironment(format!("Missing variables for the selected stages: {}, give them with --var, --var-file or --vars-from", missing.join(", "))));
+    }
+    Ok(())
+}
+
+// Runs a workflow in a new root scope that starts with its inputs.
+// Returns the output of its last stage and all of its variables.
+pub async fn execute_workflow(workflow: &Workflow, ctx: &Context<'_>, inputs: HashMap<String, StageOutput>) -> Result<(StageOutput, HashMap<String, StageOutput>), Error> {
+    execute(workflow, ctx, inputs, None).await
+}
+
+// With a run state, top-level stages that already finished are skipped and the state is saved after every stage
+async fn execute(workflow: &Workflow, ctx: &Context<'_>, mut variables: HashMap<String, StageOutput>, mut state: Option<&mut RunState>) -> Result<(StageOutput, HashMap<String, StageOutput>), Error> {
+    let root_variables = HashMap::new();
+    let exports = Exports::default();
+    let ctx = Context {
+        variables: &root_variables,
+        exports: &exports,
+        default_timeout: workflow.timeout.map(|timeout| timeout.0),
+        ..ctx.derive(&root_variables)
+    };
+    for (name, prompt) in &ctx.workflows.prompts {
+        variables.insert(format!("prompts.{}", name), StageOutput::Text(prompt.clone()));
+    }
+
+    log::info!("Running workflow {}", workflow.name);
+    let output = match workflow.execution {
+        ExecutionMode::Sequential => {
+            let mut output = StageOutput::None;
+            for (i, stage) in workflow.stages.iter().enumerate() {
+                if state.as_ref().is_some_and(|state| state.completed.contains(&i)) {
+                    log::info!("Stage {} already finished", stage.name);
+                    output = variables.get(&stage.name).cloned().unwrap_or(StageOutput::None);
+                    continue;
+                }
+                let result = run_stage(stage, &ctx, &variables).await?;
+                output = store_result(stage, &mut variables, result);
+                if let Some(state) = state.as_deref_mut() {
+                    state.checkpoint(i, &variables)?;
+                }
+            }
+            output
+        },
+        ExecutionMode::Graph => {
+            graph::run_graph(&workflow.stages, &ctx, &mut variables, state).await?;
+            workflow.stages.last()
+                .and_then(|stage| variables.get(&stage.name).cloned())
+                .unwrap_or(StageOutput::None)
+        },
+    };
+
+    Ok((output, variables))
+}
+
+// The declared outputs of a workflow, or all of its variables except prompts when it declares none
+pub fn workflow_outputs(workflow: &Workflow, mut variables: HashMap<String, StageOutput>) -> Vec<(String, StageOutput)> {
+    if workflow.outputs.is_empty() {
+        variables.into_iter()
+            .filter(|(name, _)| !name.starts_with("prompts."))
+            .collect()
+    } else {
+        workflow.outputs.iter()
+            .map(|name| (name.clone(), variables.remove(name).unwrap_or(StageOutput::None)))
+            .collect()
+    }
+}
+
+// Runs stages one after another in the given scope and returns the last output.
+// Variables exported by block stages are added to the scope right after the stage output.
+pub async fn run_stages(stages: &[WorkflowStageData], ctx: &Context<'_>, variables: &mut HashMap<String, StageOutput>) -> Result<StageOutput, Error> {
+    let mut last_output = StageOutput::None;
+    for stage in stages {
+        let result = run_stage(stage, ctx, variables).await?;
+        last_output = store_result(stage, variables, result);
+    }
+    Ok(last_output)
+}
+
+type StageResult = (StageOutput, Vec<(String, StageOutput)>);
+
+async fn run_stage(stage: &WorkflowStageData, ctx: &Context<'_>, variables: &HashMap<String, StageOutput>) -> Result<StageResult, Error> {
+    log::info!("Running stage {}", stage.name);
+    let runner = stages::get_runner(stage);
+    let exports = Exports::default();
+    let path: Vec<String> = ctx.stage_path.iter().cloned().chain(std::iter::once(stage.name.clone())).collect();
+    ctx.record(Event::StageStarted { path: &path });
+    let start = Instant::now();
+    let result = {
+        let stage_ctx = Context {
+            stage_name: &stage.name,
+            stage_path: &path,
+            ..ctx.scoped(variables, &exports)
+        };
+        runner.run(&stage_ctx).await
+    };
+    let duration_ms = start.elapsed().as_millis();
+    let output = match result {
+        Ok(output) => {
+            ctx.record(Event::StageFinished { path: &path, duration_ms, output: &output });
+            output
+        },
+        Err(e) => {
+            ctx.record(Event::StageFailed { path: &path, duration_ms, kind: e.kind(), error: e.root().to_string() });
+            return Err(e);
+        },
+    };
+    log::debug!("Stage {} output: {:?}", stage.name, output);
+    Ok((output, exports.take()))
+}
+
+fn store_result(stage: &WorkflowStageData, variables: &mut HashMap<String, StageOutput>, (output, exports): StageResult) -> StageOutput {
+    variables.insert(stage.name.clone(), output.clone());
+    for (name, value) in exports {
+        log::debug!("Stage {} exported {}: {:?}", stage.name, name, value);
+        variables.insert(name, value);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workflow() -> Workflow {
+        let workflows: Workflows = serde_yaml::from_str(r#"
+workflows:
+  - name: test
+    inputs: [{ name: topic }]
+    stages:
+      - { name: call, stage: { type: call_workflow, workflow: other, inputs: {} } }
+      - { name: draft, stage: { type: set, value: "${topic}" } }
+      - { name: review, stage: { type: set, value: "${draft} ${call.summary}" } }
+      - { name: publish, stage: { type: set, value: "${review}" } }
+  - name: other
+    stages: []
+"#).unwrap();
+        workflows.workflows[0].clone()
+    }
+
+    fn selected(from: Option<&str>, until: Option<&str>) -> Result<Vec<String>, Error> {
+        let options = RunOptions { from: from.map(String::from), until: until.map(String::from), ..RunOptions::default() };
+        Ok(select_stages(&workflow(), &options)?.stages.into_iter().map(|stage| stage.name).collect())
+    }
+
+    #[test]
+    fn test_select_stages() {
+        assert_eq!(selected(None, None).unwrap(), vec!["call", "draft", "review", "publish"]);
+        assert_eq!(selected(Some("draft"), Some("review")).unwrap(), vec!["draft", "review"]);
+        assert_eq!(selected(Some("review"), None).unwrap(), vec!["review", "publish"]);
+        assert_eq!(selected(None, Some("draft")).unwrap(), vec!["call", "draft"]);
+        // `--only` selects from and until the same stage
+        assert_eq!(selected(Some("review"), Some("review")).unwrap(), vec!["review"]);
+        assert!(matches!(selected(Some("publish"), Some("draft")), Err(Error::InvalidWorkflow(_))));
+        assert!(matches!(selected(Some("missing"), None), Err(Error::InvalidWorkflow(_))));
+        assert!(matches!(selected(None, Some("missing")), Err(Error::InvalidWorkflow(_))));
+    }
+
+    #[test]
+    fn test_check_given() {
+        let workflow = workflow();
+        let options = RunOptions { from: Some("review".to_string()), until: Some("review".to_string()), ..RunOptions::default() };
+        let selected = select_stages(&workflow, &options).unwrap();
+        let given = |names: &[&str]| -> HashMap<String, StageOutput> {
+            names.iter().map(|name| (name.to_string(), StageOutput::Text("x".to_string()))).collect()
+        };
+
+        let error = check_given(&workflow, &selected, &given(&[])).unwrap_err();
+        assert!(error.to_string().contains("call, draft"), "{}", error);
+        let error = check_given(&workflow, &selected, &given(&["call.summary"])).unwrap_err();
+        assert!(error.to_string().contains(": draft,"), "{}", error);
+        assert!(check_given(&workflow, &selected, &given(&["draft", "call.summary"])).is_ok());
+
+        let options = RunOptions { until: Some("draft".to_string()), ..RunOptions::default() };
+        let selected = select_stages(&workflow, &options).unwrap();
+        assert!(check_given(&workflow, &selected, &given(&[])).unwrap_err().to_string().contains("topic"));
+        assert!(check_given(&workflow, &selected, &given(&["topic"])).is_ok());
+    }
+}
@@ -0,0 +1,69 @@
+use std::{future::Future, sync::atomic::{AtomicBool, Ordering}};
+
+use tokio::sync::Notify;
+
+use crate::error::Error;
+
+// Set when the run is interrupted with Ctrl-C. Running stages are dropped, which kills their
+// child processes, and stages that did not start yet fail with `Error::Interrupted`.
+#[derive(Debug, Default)]
+pub struct Cancellation {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Resolves once the run is cancelled
+    pub async fn cancelled(&self) {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // Registers the waiter before checking the flag, so a cancellation in between is not missed
+        notified.as_mut().enable();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await;
+    }
+
+    // Runs the future unless the run is cancelled before it finishes
+    pub async fn guard<T, F: Future<Output = Result<T, Error>>>(&self, future: F) -> Result<T, Error> {
+        if self.is_cancelled() {
+            return Err(Error::Interrupted);
+        }
+        tokio::select! {
+            result = future => result,
+            _ = self.cancelled() => Err(Error::Interrupted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_guard() {
+        let cancellation = Cancellation::default();
+        let result = cancellation.guard(async { Ok::<_, Error>(1) }).await;
+        assert_eq!(result.unwrap(), 1);
+
+        let slow = cancellation.guard(async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        });
+        let (result, _) = tokio::join!(slow, async { cancellation.cancel() });
+        assert!(matches!(result, Err(Error::Interrupted)));
+        assert!(matches!(cancellation.guard(async { Ok(()) }).await, Err(Error::Interrupted)));
+    }
+}
@@ -0,0 +1,218 @@
+use std::{collections::HashMap, hash::Hasher, path::{Path, PathBuf}, time::SystemTime};
+
+use fnv::FnvHasher;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, schema::{Workflow, Workflows}};
+
+use super::stages::StageOutput;
+
+const RUNS_DIR: &str = ".yamlchain/runs";
+const KEPT_FINISHED_RUNS: usize = 20;
+
+// Variables of a run after its last finished top-level stage, saved in the working directory
+// so that a failed or interrupted run can be resumed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunState {
+    pub id: String,
+    pub workflow: String,
+    // Hash of all workflow definitions, a run is not resumed when they changed
+    pub definition: String,
+    // Indices of the top-level stages that finished
+    pub completed: Vec<usize>,
+    pub finished: bool,
+    pub variables: HashMap<String, StageOutput>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl RunState {
+    pub fn new(workdir: &Path, workflows: &Workflows, workflow: &Workflow, variables: HashMap<String, StageOutput>) -> Self {
+        let timestamp = humantime::format_rfc3339_seconds(SystemTime::now()).to_string().replace(['-', ':'], "");
+        let name: String = workflow.name.chars()
+            .map(|c| if c.is_alphanumeric() || c == '_' || c == '.' { c } else { '-' })
+            .collect();
+        let id = format!("{}-{}", name, timestamp);
+        Self {
+            path: state_path(workdir, &id),
+            id,
+            workflow: workflow.name.clone(),
+            definition: definition_hash(workflows),
+            completed: vec![],
+            finished: false,
+            variables,
+        }
+    }
+
+    // Saves the state of a new run right away, the id gets a `-2`, `-3`... suffix when runs started in the same second
+    pub fn reserve(&mut self, workdir: &Path) -> Result<(), Error> {
+        let dir = workdir.join(RUNS_DIR);
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| Error::InvalidEnvironment(format!("Cannot create {}: {}", dir.display(), e)))?;
+        let base = std::mem::take(&mut self.id);
+        let mut suffix = 1;
+        self.id = loop {
+            let id = match suffix {
+                1 => base.clone(),
+                suffix => format!("{}-{}", base, suffix),
+            };
+            // Creating the file reserves the id, also against runs in other processes
+            match std::fs::File::create_new(state_path(workdir, &id)) {
+                Ok(_) => break id,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => suffix += 1,
+                Err(e) => return Err(Error::InvalidEnvironment(format!("Cannot save run {}: {}", id, e))),
+            }
+        };
+        self.path = state_path(workdir, &self.id);
+        self.save()
+    }
+
+    pub fn read(workdir: &Path, id: &str) -> Result<Self, Error> {
+        let path = state_path(workdir, id);
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| Error::InvalidEnvironment(format!("Cannot read run {} from {}: {}", id, path.display(), e)))?;
+        let state: RunState = serde_json::from_str(&content)
+            .map_err(|e| Error::InvalidEnvironment(format!("Cannot parse run {}: {}", id, e)))?;
+        Ok(Self { path, ..state })
+    }
+
+    // Reads a run and checks that it can be continued with the current definitions
+    pub fn resume(workdir: &Path, id: &str, workflows: &Workflows, workflow: &Workflow) -> Result<Self, Error> {
+        let state = Self::read(workdir, id)?;
+        if state.workflow != workflow.name {
+            return Err(Error::InvalidWorkflow(format!("Run {} is a run of workflow {}, not {}", id, state.workflow, workflow.name)));
+        }
+        if state.definition != definition_hash(workflows) {
+            return Err(Error::InvalidWorkflow(format!("Workflows changed since run {} was saved, it cannot be resumed", id)));
+        }
+        if state.finished {
+            return Err(Error::InvalidWorkflow(format!("Run {} already finished", id)));
+        }
+        Ok(state)
+    }
+
+    pub fn checkpoint(&mut self, index: usize, variables: &HashMap<String, StageOutput>) -> Result<(), Error> {
+        self.completed.push(index);
+        self.variables = variables.clone();
+        self.save()
+    }
+
+    // Finished runs are kept for `--vars-from`, only the most recent ones so that the directory does not grow
+    pub fn finish(&mut self) -> Result<(), Error> {
+        self.finished = true;
+        self.save()?;
+        if let Some(dir) = self.path.parent() {
+            prune_finished(dir);
+        }
+        Ok(())
+    }
+
+    // Writes to a temporary file first, so an interrupted write keeps the previous checkpoint
+    fn save(&self) -> Result<(), Error> {
+        let error = |e: std::io::Error| Error::RuntimeError(format!("Cannot save run {}: {}", self.id, e));
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).map_err(error)?;
+        }
+        let content = serde_json::to_string_pretty(self).map_err(|e| Error::RuntimeError(e.to_string()))?;
+        let tmp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, content).map_err(error)?;
+        std::fs::rename(&tmp_path, &self.path).map_err(error)
+    }
+}
+
+// Removes the finished runs beyond the `KEPT_FINISHED_RUNS` most recent ones
+fn prune_finished(dir: &Path) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return log::warn!("Cannot list runs in {}: {}", dir.display(), e),
+    };
+    let mut finished: Vec<(SystemTime, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .filter(|path| std::fs::read_to_string(path).ok()
+            .and_then(|content| serde_json::from_str::<RunState>(&content).ok())
+            .is_some_and(|state| state.finished))
+        .filter_map(|path| Some((std::fs::metadata(&path).ok()?.modified().ok()?, path)))
+        .collect();
+    finished.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    for (_, path) in finished.into_iter().skip(KEPT_FINISHED_RUNS) {
+        if let Err(e) = std::fs::remove_file(&path) {
+            log::warn!("Cannot remove run {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn state_path(workdir: &Path, id: &str) -> PathBuf {
+    workdir.join(RUNS_DIR).join(format!("{}.json", id))
+}
+
+fn definition_hash(workflows: &Workflows) -> String {
+    let definition = serde_json::to_string(workflows).unwrap_or_default();
+    let mut hasher = FnvHasher::default();
+    hasher.write(definition.as_bytes());
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workflows(value: &str) -> Workflows {
+        serde_yaml::from_str(&format!("workflows:\n  - name: test\n    stages:\n      - {{ name: a, stage: {{ type: set, value: {} }} }}\n", value)).unwrap()
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = workflows("x");
+        let mut state = RunState::new(dir.path(), &original, &original.workflows[0], HashMap::new());
+        let mut variables = HashMap::new();
+        variables.insert("a".to_string(), StageOutput::List(vec!["x".to_string()]));
+        variables.insert("b".to_string(), StageOutput::None);
+        state.checkpoint(0, &variables).unwrap();
+
+        let resumed = RunState::resume(dir.path(), &state.id, &original, &original.workflows[0]).unwrap();
+        assert_eq!(resumed.completed, vec![0]);
+        assert!(matches!(resumed.variables.get("a"), Some(StageOutput::List(list)) if list == &vec!["x".to_string()]));
+        assert!(matches!(resumed.variables.get("b"), Some(StageOutput::None)));
+
+        let changed = workflows("y");
+        assert!(RunState::resume(dir.path(), &state.id, &changed, &changed.workflows[0]).is_err());
+    }
+
+    #[test]
+    fn test_unique_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let workflows = workflows("x");
+        let ids: Vec<String> = (0..3)
+            .map(|_| {
+                let mut state = RunState::new(dir.path(), &workflows, &workflows.workflows[0], HashMap::new());
+                state.reserve(dir.path()).unwrap();
+                state.id
+            })
+            .collect();
+        assert_ne!(ids[0], ids[1]);
+        assert_ne!(ids[1], ids[2]);
+        assert_ne!(ids[0], ids[2]);
+        for id in &ids {
+            assert!(RunState::resume(dir.path(), id, &workflows, &workflows.workflows[0]).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_prune_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let workflows = workflows("x");
+        let mut unfinished = RunState::new(dir.path(), &workflows, &workflows.workflows[0], HashMap::new());
+        unfinished.reserve(dir.path()).unwrap();
+        for _ in 0..KEPT_FINISHED_RUNS + 3 {
+            let mut state = RunState::new(dir.path(), &workflows, &workflows.workflows[0], HashMap::new());
+            state.reserve(dir.path()).unwrap();
+            state.finish().unwrap();
+        }
+        let saved = std::fs::read_dir(dir.path().join(RUNS_DIR)).unwrap().count();
+        assert_eq!(saved, KEPT_FINISHED_RUNS + 1);
+        assert!(RunState::resume(dir.path(), &unfinished.id, &workflows, &workflows.workflows[0]).is_ok());
+    }
+}
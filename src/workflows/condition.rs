@@ -0,0 +1,451 @@
+use std::str::FromStr;
+
+use regex::Regex;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+use super::{stages::StageOutput, Context};
+
+/// A boolean expression over interpolated variables, parsed when the workflow is loaded.
+///
+/// Supports `==`, `!=`, `<`, `<=`, `>`, `>=`, `contains`, `matches` (regex),
+/// `len()`, `is_empty()`, `and`, `or`, `not` and parentheses.
+/// Example: `len(${files}) > 0 and ${answer} contains "yes"`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Variable(String),
+    Text(String),
+    Number(f64),
+    Bool(bool),
+    Len(Box<Expr>),
+    IsEmpty(Box<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Box<Expr>, Operator, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+    Matches,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Variable(String),
+    Text(String),
+    Number(f64),
+    Ident(String),
+    Operator(Operator),
+    LeftParen,
+    RightParen,
+}
+
+#[derive(Debug)]
+enum Value {
+    Text(String),
+    List(Vec<String>),
+    Number(f64),
+    Bool(bool),
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let error = |msg: String| Error::InvalidWorkflow(format!("Invalid condition `{}`: {}", source, msg));
+        let tokens = tokenize(source).map_err(error)?;
+        let mut parser = Parser { tokens, position: 0 };
+        let expr = parser.parse_or().map_err(error)?;
+        if let Some(token) = parser.peek() {
+            return Err(error(format!("unexpected {:?}", token)));
+        }
+        if !expr.is_boolean() {
+            return Err(error("the expression does not evaluate to a boolean".to_string()));
+        }
+        Ok(Self { source: source.to_string(), expr })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn evaluate(&self, ctx: &Context<'_>) -> Result<bool, Error> {
+        match evaluate(&self.expr, ctx)? {
+            Value::Bool(b) => Ok(b),
+            _ => Err(Error::RuntimeError(format!("Condition `{}` did not evaluate to a boolean", self.source))),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl TryFrom<String> for Condition {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(&value)
+    }
+}
+
+impl From<Condition> for String {
+    fn from(condition: Condition) -> Self {
+        condition.source
+    }
+}
+
+impl JsonSchema for Condition {
+    fn schema_name() -> String {
+        "Condition".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
+impl Expr {
+    fn is_boolean(&self) -> bool {
+        match self {
+            Expr::Bool(_) | Expr::IsEmpty(_) | Expr::Compare(..) => true,
+            Expr::Not(e) => e.is_boolean(),
+            Expr::And(l, r) | Expr::Or(l, r) => l.is_boolean() && r.is_boolean(),
+            _ => false,
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => { tokens.push(Token::LeftParen); i += 1; },
+            ')' => { tokens.push(Token::RightParen); i += 1; },
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                let end = chars[i..].iter().position(|c| *c == '}')
+                    .ok_or("unclosed variable reference")?;
+                tokens.push(Token::Variable(chars[i + 2..i + end].iter().collect()));
+                i += end + 1;
+            },
+            '"' | '\'' => {
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err("unclosed string".to_string()),
+                        Some('\\') => {
+                            text.push(*chars.get(i + 1).ok_or("unclosed string")?);
+                            i += 2;
+                        },
+                        Some(q) if *q == c => { i += 1; break; },
+                        Some(ch) => { text.push(*ch); i += 1; },
+                    }
+                }
+                tokens.push(Token::Text(text));
+            },
+            '=' | '!' | '<' | '>' => {
+                let double = chars.get(i + 1) == Some(&'=');
+                let operator = match (c, double) {
+                    ('=', true) => Operator::Eq,
+                    ('!', true) => Operator::Ne,
+                    ('<', true) => Operator::Le,
+                    ('>', true) => Operator::Ge,
+                    ('<', false) => Operator::Lt,
+                    ('>', false) => Operator::Gt,
+                    _ => return Err(format!("unexpected character '{}'", c)),
+                };
+                tokens.push(Token::Operator(operator));
+                i += if double { 2 } else { 1 };
+            },
+            c if c.is_ascii_digit() || (c == '-' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit())) => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let number: String = chars[start..i].iter().collect();
+                tokens.push(Token::Number(number.parse().map_err(|_| format!("invalid number {}", number))?));
+            },
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let ident: String = chars[start..i].iter().collect();
+                tokens.push(match ident.as_str() {
+                    "contains" => Token::Operator(Operator::Contains),
+                    "matches" => Token::Operator(Operator::Matches),
+                    _ => Token::Ident(ident),
+                });
+            },
+            _ => return Err(format!("unexpected character '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Ident(ident)) if ident == keyword) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("expected {:?}, found {:?}", expected, token)),
+            None => Err(format!("expected {:?}, found end of expression", expected)),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_and()?;
+        while self.eat_keyword("or") {
+            left = Expr::Or(Box::new(left), Box::new(self.parse_and()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_not()?;
+        while self.eat_keyword("and") {
+            left = Expr::And(Box::new(left), Box::new(self.parse_not()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, String> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, String> {
+        let left = self.parse_operand()?;
+        let operator = match self.peek() {
+            Some(Token::Operator(operator)) => *operator,
+            _ => return Ok(left),
+        };
+        self.position += 1;
+        let right = self.parse_operand()?;
+        if operator == Operator::Matches {
+            if let Expr::Text(pattern) = &right {
+                if !pattern.contains("${") {
+                    Regex::new(pattern).map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        Ok(Expr::Compare(Box::new(left), operator, Box::new(right)))
+    }
+
+    fn parse_operand(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Variable(name)) => Ok(Expr::Variable(name)),
+            Some(Token::Text(text)) => Ok(Expr::Text(text)),
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::LeftParen) => {
+                let expr = self.parse_or()?;
+                self.expect(Token::RightParen)?;
+                Ok(expr)
+            },
+            Some(Token::Ident(ident)) => match ident.as_str() {
+                "true" => Ok(Expr::Bool(true)),
+                "false" => Ok(Expr::Bool(false)),
+                "len" | "is_empty" => {
+                    self.expect(Token::LeftParen)?;
+                    let argument = Box::new(self.parse_or()?);
+                    self.expect(Token::RightParen)?;
+                    Ok(if ident == "len" { Expr::Len(argument) } else { Expr::IsEmpty(argument) })
+                },
+                _ => Err(format!("unknown identifier {}, strings need to be quoted", ident)),
+            },
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn evaluate(expr: &Expr, ctx: &Context<'_>) -> Result<Value, Error> {
+    Ok(match expr {
+        Expr::Variable(name) => match ctx.get_variable(name)? {
+            StageOutput::Text(s) => Value::Text(s.clone()),
+            StageOutput::List(vec) => Value::List(vec.clone()),
+            StageOutput::None => Value::Text(String::new()),
+        },
+        Expr::Text(text) => Value::Text(ctx.interpolate(text)?),
+        Expr::Number(n) => Value::Number(*n),
+        Expr::Bool(b) => Value::Bool(*b),
+        Expr::Len(e) => Value::Number(match evaluate(e, ctx)? {
+            Value::List(vec) => vec.len(),
+            value => value.text().chars().count(),
+        } as f64),
+        Expr::IsEmpty(e) => Value::Bool(match evaluate(e, ctx)? {
+            Value::List(vec) => vec.is_empty(),
+            value => value.text().trim().is_empty(),
+        }),
+        Expr::Not(e) => Value::Bool(!evaluate(e, ctx)?.boolean()?),
+        Expr::And(l, r) => Value::Bool(evaluate(l, ctx)?.boolean()? && evaluate(r, ctx)?.boolean()?),
+        Expr::Or(l, r) => Value::Bool(evaluate(l, ctx)?.boolean()? || evaluate(r, ctx)?.boolean()?),
+        Expr::Compare(l, operator, r) => Value::Bool(compare(evaluate(l, ctx)?, *operator, evaluate(r, ctx)?)?),
+    })
+}
+
+fn compare(left: Value, operator: Operator, right: Value) -> Result<bool, Error> {
+    Ok(match operator {
+        Operator::Eq | Operator::Ne => {
+            let equal = match (left.number(), right.number()) {
+                (Some(l), Some(r)) => l == r,
+                _ => left.text().trim() == right.text().trim(),
+            };
+            equal == (operator == Operator::Eq)
+        },
+        Operator::Lt | Operator::Le | Operator::Gt | Operator::Ge => {
+            let (l, r) = match (left.number(), right.number()) {
+                (Some(l), Some(r)) => (l, r),
+                _ => return Err(Error::VariableTypeMismatch(format!("Cannot compare {} and {} as numbers", left.text(), right.text()))),
+            };
+            match operator {
+                Operator::Lt => l < r,
+                Operator::Le => l <= r,
+                Operator::Gt => l > r,
+                _ => l >= r,
+            }
+        },
+        Operator::Contains => match left {
+            Value::List(vec) => vec.iter().any(|item| item.trim() == right.text().trim()),
+            left => left.text().contains(&right.text()),
+        },
+        Operator::Matches => {
+            let re = Regex::new(&right.text()).map_err(|e| Error::RuntimeError(e.to_string()))?;
+            re.is_match(&left.text())
+        },
+    })
+}
+
+impl Value {
+    fn text(&self) -> String {
+        match self {
+            Value::Text(s) => s.clone(),
+            Value::List(vec) => vec.join("\n"),
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+        }
+    }
+
+    fn number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Text(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    fn boolean(&self) -> Result<bool, Error> {
+        match self {
+            Value::Bool(b) => Ok(*b),
+            value => Err(Error::VariableTypeMismatch(format!("{} is not a boolean", value.text()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::interface::cli::CliInterface;
+    use crate::llm::OpenAi;
+    use crate::schema::Workflows;
+    use crate::workflows::{cancel::Cancellation, Exports, RunSummary};
+
+    use super::*;
+
+    fn check(condition: &str) -> bool {
+        let mut variables = HashMap::new();
+        variables.insert("answer".to_string(), StageOutput::Text(" Yes \n".to_string()));
+        variables.insert("count".to_string(), StageOutput::Text("12".to_string()));
+        variables.insert("files".to_string(), StageOutput::List(vec!["a.rs".to_string(), "b.rs".to_string()]));
+        variables.insert("nothing".to_string(), StageOutput::None);
+        let interface = CliInterface;
+        let exports = Exports::default();
+        let ctx = Context {
+            variables: &variables,
+            interface: &interface,
+            llm: &OpenAi,
+            workdir: std::path::Path::new("."),
+            exports: &exports,
+            default_timeout: None,
+            workflows: &Workflows::default(),
+            stage_name: "",
+            stage_path: &[],
+            journal: None,
+            summary: &RunSummary::default(),
+            dry_run: None,
+            cancellation: &Cancellation::default(),
+            depth: 0,
+        };
+        Condition::parse(condition).unwrap().evaluate(&ctx).unwrap()
+    }
+
+    #[test]
+    fn test_comparisons() {
+        assert!(check(r#"${answer} == "Yes""#));
+        assert!(check(r#"${answer} != 'no'"#));
+        assert!(check("${count} > 9 and ${count} <= 12"));
+        assert!(check(r#"${files} contains "b.rs" and not (${answer} matches "^no")"#));
+        assert!(check("len(${files}) == 2 or false"));
+        assert!(check("is_empty(${nothing}) and not is_empty(${files})"));
+    }
+
+    #[test]
+    fn test_invalid_conditions() {
+        assert!(Condition::parse("${answer}").is_err());
+        assert!(Condition::parse("${answer} == yes").is_err());
+        assert!(Condition::parse(r#"${answer} matches "(""#).is_err());
+        assert!(Condition::parse("len(${files}) >").is_err());
+        assert!(Condition::parse("(true").is_err());
+    }
+}
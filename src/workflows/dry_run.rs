@@ -0,0 +1,137 @@
+use std::{collections::HashMap, path::Path};
+
+use crate::error::Error;
+
+use super::{stages::StageOutput, Context};
+
+// Replaces stages that cost money or have side effects when running with `--dry-run`.
+// Canned outputs are read from a YAML file that maps stage names to texts or lists.
+#[derive(Debug, Default)]
+pub struct DryRun {
+    canned: HashMap<String, StageOutput>,
+}
+
+impl DryRun {
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let canned = match path {
+            Some(path) => {
+                let file = std::fs::File::open(path)
+                    .map_err(|e| Error::InvalidEnvironment(format!("Cannot open {}: {}", path.display(), e)))?;
+                serde_yaml::from_reader(file)
+                    .map_err(|e| Error::InvalidEnvironment(format!("Invalid canned outputs in {}: {}", path.display(), e)))?
+            },
+            None => HashMap::new(),
+        };
+        Ok(Self { canned })
+    }
+
+    // Shows the interpolated inputs of the current stage and returns its canned output or the placeholder
+    pub async fn output(&self, ctx: &Context<'_>, inputs: &[(&str, &str)], placeholder: StageOutput) -> Result<StageOutput, Error> {
+        let mut message = format!("[dry run] {}", ctx.stage_name);
+        for (name, value) in inputs {
+            message.push_str(&format!("\n  {}:", name));
+            for line in value.lines() {
+                message.push_str(&format!("\n    {}", line));
+            }
+        }
+        ctx.interface.send_message(message).await?;
+        Ok(self.canned.get(ctx.stage_name).cloned().unwrap_or(placeholder))
+    }
+
+    // Like `output`, for model calls made inside a stage that need a text
+    pub async fn text(&self, ctx: &Context<'_>, inputs: &[(&str, &str)]) -> Result<String, Error> {
+        match self.output(ctx, inputs, Self::placeholder(ctx)).await? {
+            StageOutput::Text(text) => Ok(text),
+            StageOutput::List(list) => Ok(list.join("\n")),
+            StageOutput::None => Ok(String::new()),
+        }
+    }
+
+    pub fn placeholder(ctx: &Context<'_>) -> StageOutput {
+        StageOutput::Text(format!("<output of {}>", ctx.stage_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+
+    use crate::{engine::Engine, interface::Interface, llm::{LlmProvider, Message, Response}, workflows::RunOptions};
+
+    use super::*;
+
+    struct NoModel;
+
+    #[async_trait]
+    impl LlmProvider for NoModel {
+        async fn complete(&self, _messages: Vec<Message>, _model: &str) -> Result<Response, Error> {
+            panic!("A dry run called the model");
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Messages(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Interface for Messages {
+        async fn send_message(&self, msg: String) -> Result<(), Error> {
+            self.0.lock().unwrap().push(msg);
+            Ok(())
+        }
+        async fn get_input(&self, msg: String) -> Result<String, Error> {
+            panic!("Unexpected question {}", msg);
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(DryRun::load(None).unwrap().canned.is_empty());
+
+        let path = dir.path().join("canned.yaml");
+        std::fs::write(&path, "idea: A canned idea\nfiles: [a.rs, b.rs]\n").unwrap();
+        let dry_run = DryRun::load(Some(&path)).unwrap();
+        assert!(matches!(dry_run.canned.get("idea"), Some(StageOutput::Text(text)) if text == "A canned idea"));
+        assert!(matches!(dry_run.canned.get("files"), Some(StageOutput::List(list)) if list == &["a.rs", "b.rs"]));
+
+        std::fs::write(&path, "idea: { nested: map }\n").unwrap();
+        assert!(matches!(DryRun::load(Some(&path)), Err(Error::InvalidEnvironment(_))));
+        assert!(matches!(DryRun::load(Some(&dir.path().join("missing.yaml"))), Err(Error::InvalidEnvironment(_))));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let canned = dir.path().join("canned.yaml");
+        std::fs::write(&canned, "idea: A canned idea\n").unwrap();
+        let messages = Messages::default();
+        let engine = Engine::builder()
+            .workflows_str(r#"
+workflows:
+  - name: test
+    stages:
+      - { name: idea, stage: { type: ai_processing, model: gpt-4, system_message: "Be brief", prompt: "An idea" } }
+      - { name: summary, stage: { type: ai_processing, model: gpt-4, system_message: "Be brief", prompt: "Summarize ${idea}" } }
+      - { name: touch, stage: { type: shell_command, command: touch, args: [ran.txt] } }
+      - { name: save, stage: { type: save_file, path: saved.txt, content: "${summary}" } }
+      - { name: result, stage: { type: set, value: "${idea} / ${summary}" } }
+"#)
+            .interface(messages.clone())
+            .llm(NoModel)
+            .workdir(dir.path())
+            .build()
+            .unwrap();
+        let options = RunOptions { dry_run: Some(DryRun::load(Some(&canned)).unwrap()), ..RunOptions::default() };
+        let outputs = engine.run_with(Some("test"), &options).await.unwrap();
+
+        assert_eq!(outputs.text("result"), Some("A canned idea / <output of summary>"));
+        assert!(!dir.path().join("ran.txt").exists());
+        assert!(!dir.path().join("saved.txt").exists());
+        let messages = messages.0.lock().unwrap();
+        assert!(messages.iter().any(|msg| msg.starts_with("[dry run] summary") && msg.contains("Summarize A canned idea")), "{:?}", messages);
+        let saved_runs = std::fs::read_dir(dir.path().join(".yamlchain/runs")).map_or(0, |entries| entries.count());
+        assert_eq!(saved_runs, 0);
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::{HashMap, HashSet};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use regex::Regex;
+
+use crate::{error::Error, schema::{WorkflowStage, WorkflowStageData, Workflows}};
+use crate::workflows::stages::for_each::{ForEachSource, ForEachStageInfo};
+
+use super::{checkpoint::RunState, references::StageTexts, stages::StageOutput, Context};
+
+// Finds the stages that every stage depends on, by index.
+// A stage depends on the stages whose names it references with `${name}`, or by a bare name
+// like `for_each.list`. Interactive stages also depend on the previous interactive stage, and
+// plugins that get all variables depend on every earlier stage.
+pub fn dependencies(stages: &[WorkflowStageData], workflows: &Workflows) -> Result<Vec<Vec<usize>>, Error> {
+    let mut defined_at: HashMap<&str, usize> = HashMap::new();
+    for (i, stage) in stages.iter().enumerate() {
+        for name in stage.defined_names() {
+            if defined_at.insert(name, i).is_some() {
+                return Err(Error::InvalidWorkflow(format!("{} is defined more than once, which is not allowed in graph execution", name)));
+            }
+        }
+    }
+
+    let mut dependencies = Vec::new();
+    let mut last_interactive = None;
+    for (i, stage) in stages.iter().enumerate() {
+        let local = local_names(stage);
+        let mut stage_dependencies = HashSet::new();
+        let (references, mentions) = references(stage, defined_at.keys().copied());
+        for name in mentions {
+            let defined = defined_at[name];
+            if defined < i && !local.contains(name) {
+                stage_dependencies.insert(defined);
+            }
+        }
+        for name in references {
+            if local.contains(name) {
+                continue;
+            }
+            let defined = defined_at[name];
+            if defined == i {
+                return Err(Error::InvalidWorkflow(format!("Stage {} references its own output {}", stage.name, name)));
+            }
+            if defined > i {
+                return Err(Error::InvalidWorkflow(format!("Stage {} references {}, which is defined later by stage {}", stage.name, name, stages[defined].name)));
+            }
+            stage_dependencies.insert(defined);
+        }
+        if uses_all_variables(&stage.stage) {
+            stage_dependencies.extend(0..i);
+        }
+        if interactive(&stage.stage, workflows) {
+            stage_dependencies.extend(last_interactive);
+            last_interactive = Some(i);
+        }
+        let mut stage_dependencies: Vec<usize> = stage_dependencies.into_iter().collect();
+        stage_dependencies.sort();
+        dependencies.push(stage_dependencies);
+    }
+    Ok(dependencies)
+}
+
+pub async fn run_graph(stages: &[WorkflowStageData], ctx: &Context<'_>, variables: &mut HashMap<String, StageOutput>, mut state: Option<&mut RunState>) -> Result<(), Error> {
+    let dependencies = dependencies(stages, ctx.workflows)?;
+    let mut finished = vec![false; stages.len()];
+    if let Some(state) = state.as_deref() {
+        for &i in &state.completed {
+            finished[i] = true;
+        }
+    }
+    let mut started = finished.clone();
+    let mut running = FuturesUnordered::new();
+
+    loop {
+        for (i, stage) in stages.iter().enumerate() {
+            if started[i] || !dependencies[i].iter().all(|d| finished[*d]) {
+                continue;
+            }
+            started[i] = true;
+            let snapshot = variables.clone();
+            running.push(async move {
+                let result = super::run_stage(stage, ctx, &snapshot).await;
+                (i, result)
+            });
+        }
+        match running.next().await {
+            Some((i, result)) => {
+                super::store_result(&stages[i], variables, result?);
+                finished[i] = true;
+                if let Some(state) = state.as_deref_mut() {
+                    state.checkpoint(i, variables)?;
+                }
+            },
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+// Whether the stage, or a stage inside it or in a workflow it calls, interacts with the user
+pub fn interactive(stage: &WorkflowStage, workflows: &Workflows) -> bool {
+    is_interactive(stage, workflows, &mut HashSet::new())
+}
+
+// `called` holds the workflows already looked into, so recursive calls are followed only once
+fn is_interactive<'a>(stage: &'a WorkflowStage, workflows: &'a Workflows, called: &mut HashSet<&'a str>) -> bool {
+    match stage {
+        // Plugins can ask the user too
+        stage if stage.waits_for_user() => true,
+        WorkflowStage::Plugin(_) => true,
+        WorkflowStage::CallWorkflow(info) => {
+            if !called.insert(info.workflow.as_str()) {
+                return false;
+            }
+            workflows.workflows.iter()
+                .filter(|workflow| workflow.name == info.workflow)
+                .flat_map(|workflow| workflow.stages.iter())
+                .any(|s| is_interactive(&s.stage, workflows, called))
+        },
+        stage => stage.nested_stages().iter()
+            .flat_map(|stages| stages.iter())
+            .any(|s| is_interactive(&s.stage, workflows, called)),
+    }
+}
+
+// Plugins without `variables` get the whole scope they run in
+fn uses_all_variables(stage: &WorkflowStage) -> bool {
+    match stage {
+        WorkflowStage::Plugin(info) => info.variables.is_none(),
+        stage => stage.nested_stages().iter()
+            .flat_map(|stages| stages.iter())
+            .any(|s| uses_all_variables(&s.stage)),
+    }
+}
+
+// Names from `names` that a stage takes from the scope it runs in
+pub fn outer_references<'a>(stage: &WorkflowStageData, names: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let local = local_names(stage);
+    references(stage, names).0.into_iter()
+        .filter(|name| !local.contains(name))
+        .collect()
+}
+
+// Names defined inside a block stage, which shadow names from the outer scope
+fn local_names(stage: &WorkflowStageData) -> HashSet<&str> {
+    let mut names = HashSet::new();
+    if let WorkflowStage::ForEach(info) = &stage.stage {
+        names.extend([info.variable.as_str(), "loop"]);
+    }
+    for inner in stage.stage.nested_stages().iter().flat_map(|stages| stages.iter()) {
+        names.insert(inner.name.as_str());
+        names.extend(local_names(inner));
+    }
+    names
+}
+
+// Returns names referenced with `${name}` or by a bare name, and names that are only
+// mentioned in Jinja templates, which can use variables without `${}`.
+// Mentions are treated as dependencies only when the name is defined earlier.
+fn references<'a>(stage: &WorkflowStageData, names: impl Iterator<Item = &'a str>) -> (Vec<&'a str>, Vec<&'a str>) {
+    let texts = StageTexts::of(stage, true);
+    // `${call.summary}` references the output of the `call` stage
+    let mut referenced: HashSet<String> = texts.references().into_iter()
+        .flat_map(|name| {
+            let prefix = name.split_once('.').map(|(prefix, _)| prefix.to_string());
+            std::iter::once(name).chain(prefix)
+        })
+        .collect();
+    if let WorkflowStage::ForEach(ForEachStageInfo { source: ForEachSource::List(list), .. }) = &stage.stage {
+        referenced.insert(list.clone());
+    }
+    if let WorkflowStage::Plugin(info) = &stage.stage {
+        referenced.extend(info.variables.iter().flatten().cloned());
+    }
+    let (references, others): (Vec<&str>, Vec<&str>) = names.partition(|name| referenced.contains(*name));
+    let mentions = others.into_iter()
+        .filter(|name| {
+            // Whole identifiers only, so `goal` is not mentioned by `goals`
+            let re = Regex::new(&format!(r"\b{}\b", regex::escape(name))).unwrap();
+            texts.templates.iter().any(|t| re.is_match(t))
+        })
+        .collect();
+    (references, mentions)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::Workflows;
+
+    use super::*;
+
+    fn workflows(yaml: &str) -> Workflows {
+        serde_yaml::from_str(&format!("workflows:\n  - name: test\n    stages:\n{}", yaml)).unwrap()
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let workflows = workflows(r#"
+      - { name: goal, stage: { type: user_input, message: "Goal?" } }
+      - { name: tree, stage: { type: shell_command, command: tree } }
+      - { name: split, stage: { type: split, data: "${tree}", delimiter: ",", trim: true, remove_empty: true } }
+      - { name: answer, stage: { type: user_input, message: "Sure?" } }
+      - name: loop
+        stage:
+          type: for_each
+          list: split
+          variable: goal
+          stages:
+            - { name: inner, stage: { type: set, value: "${goal} ${answer}" } }
+"#);
+        assert_eq!(dependencies(&workflows.workflows[0].stages, &workflows).unwrap(), vec![vec![], vec![], vec![1], vec![0], vec![2, 3]]);
+    }
+
+    #[test]
+    fn test_forward_reference() {
+        let workflows = workflows(r#"
+      - { name: first, stage: { type: set, value: "${second}" } }
+      - { name: second, stage: { type: set, value: "x" } }
+"#);
+        assert!(dependencies(&workflows.workflows[0].stages, &workflows).is_err());
+    }
+
+    #[test]
+    fn test_outer_references() {
+        let workflows = workflows(r#"
+      - name: loop
+        stage:
+          type: for_each
+          list: items
+          variable: item
+          stages:
+            - { name: inner, stage: { type: set, value: "${item} ${prefix} ${call.summary}" } }
+"#);
+        let names = ["items", "item", "prefix", "call", "other"];
+        let mut references = outer_references(&workflows.workflows[0].stages[0], names.into_iter());
+        references.sort();
+        assert_eq!(references, vec!["call", "items", "prefix"]);
+    }
+
+    #[test]
+    fn test_plugin_dependencies() {
+        let workflows = workflows(r#"
+      - { name: first, stage: { type: set, value: "a" } }
+      - { name: second, stage: { type: set, value: "b" } }
+      - { name: some, stage: { type: plugin, plugin: tool, variables: [second] } }
+      - { name: all, stage: { type: plugin, plugin: tool } }
+      - { name: ask, stage: { type: user_input, message: "Sure?" } }
+"#);
+        assert_eq!(dependencies(&workflows.workflows[0].stages, &workflows).unwrap(), vec![vec![], vec![], vec![1], vec![0, 1, 2], vec![3]]);
+    }
+
+    #[test]
+    fn test_jinja_mentions() {
+        let workflows = workflows(r#"
+      - { name: goal, stage: { type: set, value: "a" } }
+      - { name: goals, stage: { type: set, value: "b" } }
+      - { name: use, stage: { type: ai_processing, model: gpt-4, engine: jinja, prompt: "{{ goals | upper }}", system_message: "x" } }
+"#);
+        assert_eq!(dependencies(&workflows.workflows[0].stages, &workflows).unwrap(), vec![vec![], vec![], vec![1]]);
+    }
+}
@@ -0,0 +1,89 @@
+use std::{fs::File, io::Write, path::{Path, PathBuf}, sync::Mutex, time::SystemTime};
+
+use serde::Serialize;
+
+use crate::{error::{Error, ErrorKind}, llm::Message};
+
+use super::stages::StageOutput;
+
+const JOURNALS_DIR: &str = ".yamlchain/journals";
+
+// Events of a run written as JSON Lines to `.yamlchain/journals/<run-id>.jsonl` when running with `--journal`.
+// Stages are identified by their path, the names of the stages they run in followed by their own name.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    RunStarted { run: &'a str, workflow: &'a str, resumed: bool },
+    RunFinished { run: &'a str, summary: String, error: Option<String> },
+    StageStarted { path: &'a [String] },
+    StageSkipped { path: &'a [String], condition: &'a str },
+    StageFinished { path: &'a [String], duration_ms: u128, output: &'a StageOutput },
+    StageFailed { path: &'a [String], duration_ms: u128, kind: ErrorKind, error: String },
+    // A template interpolated by the stage, like a prompt or a command
+    Input { path: &'a [String], template: &'a str, value: &'a str },
+    LlmRequest { path: &'a [String], model: &'a str, messages: &'a [Message] },
+    LlmResponse { path: &'a [String], duration_ms: u128, text: &'a str },
+    LlmFailed { path: &'a [String], duration_ms: u128, error: String },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    // Appends to the journal of the run, so a resumed run continues it
+    pub fn open(workdir: &Path, run_id: &str) -> Result<Self, Error> {
+        let dir = workdir.join(JOURNALS_DIR);
+        let path = dir.join(format!("{}.jsonl", run_id));
+        let error = |e: std::io::Error| Error::InvalidEnvironment(format!("Cannot open journal {}: {}", path.display(), e));
+        std::fs::create_dir_all(&dir).map_err(error)?;
+        let file = File::options().create(true).append(true).open(&path).map_err(error)?;
+        log::info!("Writing journal to {}", path.display());
+        Ok(Self { path, file: Mutex::new(file) })
+    }
+
+    // A journal that cannot be written does not stop the run
+    pub fn record(&self, event: Event) {
+        let record = Record {
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event: &event,
+        };
+        let result = serde_json::to_string(&record)
+            .map_err(std::io::Error::from)
+            .and_then(|line| writeln!(self.file.lock().unwrap(), "{}", line));
+        if let Err(e) = result {
+            log::warn!("Cannot write to journal {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::open(dir.path(), "run").unwrap();
+        let path = vec!["loop".to_string(), "inner".to_string()];
+        journal.record(Event::StageStarted { path: &path });
+        journal.record(Event::StageFinished { path: &path, duration_ms: 3, output: &StageOutput::List(vec!["a".to_string()]) });
+
+        let content = std::fs::read_to_string(dir.path().join(JOURNALS_DIR).join("run.jsonl")).unwrap();
+        let lines: Vec<serde_json::Value> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "stage_started");
+        assert_eq!(lines[0]["path"], serde_json::json!(["loop", "inner"]));
+        assert_eq!(lines[1]["output"], serde_json::json!(["a"]));
+        assert!(lines[1]["time"].is_string());
+    }
+}
@@ -0,0 +1,38 @@
+use tokio::process::{Child, Command};
+
+use crate::error::Error;
+
+// Spawns the command in its own process group. Dropping the returned guard before calling
+// `finished` kills the whole group, so processes started by scripts do not outlive a cancelled stage.
+pub fn spawn(command: &mut Command) -> Result<(Child, ProcessGroup), Error> {
+    #[cfg(unix)]
+    unsafe {
+        command.pre_exec(|| {
+            libc::setpgid(0, 0);
+            Ok(())
+        });
+    }
+    let child = command.kill_on_drop(true).spawn().map_err(|e| Error::RuntimeError(e.to_string()))?;
+    let group = ProcessGroup(child.id());
+    Ok((child, group))
+}
+
+pub struct ProcessGroup(Option<u32>);
+
+impl ProcessGroup {
+    pub fn finished(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.0 {
+            log::debug!("Killing process group {}", pid);
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use regex::Regex;
+
+use crate::schema::WorkflowStageData;
+
+// Matches `${name}`, with the name in the first group
+pub fn reference_regex() -> Regex {
+    Regex::new(r"\$\{([^}]+)\}").unwrap()
+}
+
+// Names referenced with `${name}` in a text
+pub fn references_in(text: &str) -> Vec<String> {
+    reference_regex().captures_iter(text).map(|caps| caps[1].to_string()).collect()
+}
+
+// Texts in the fields of a stage and in its `when` and `default`. Jinja templates are kept apart,
+// as they can also use variables without `${}`.
+#[derive(Debug, Default)]
+pub struct StageTexts {
+    pub strings: Vec<String>,
+    pub templates: Vec<String>,
+}
+
+impl StageTexts {
+    // With `nested`, the texts of the stages inside a block stage are included
+    pub fn of(stage: &WorkflowStageData, nested: bool) -> Self {
+        let mut texts = Self::default();
+        texts.collect(&serde_json::to_value(&stage.stage).unwrap_or_default(), nested);
+        texts.strings.extend(stage.when.iter().map(|condition| condition.source().to_string()));
+        texts.strings.extend(stage.default.iter().cloned());
+        texts
+    }
+
+    // Names referenced with `${name}` in the texts, templates included
+    pub fn references(&self) -> Vec<String> {
+        self.strings.iter().chain(&self.templates).flat_map(|s| references_in(s)).collect()
+    }
+
+    fn collect(&mut self, value: &serde_json::Value, nested: bool) {
+        match value {
+            serde_json::Value::String(s) => self.strings.push(s.clone()),
+            serde_json::Value::Array(values) => {
+                let is_stage = |value: &serde_json::Value| value.get("name").is_some() && value.get("stage").is_some();
+                if !nested && !values.is_empty() && values.iter().all(is_stage) {
+                    return;
+                }
+                values.iter().for_each(|v| self.collect(v, nested));
+            },
+            serde_json::Value::Object(map) => {
+                let jinja = map.get("engine").and_then(|engine| engine.as_str()) == Some("jinja");
+                for (_, value) in map.iter().filter(|(key, _)| key.as_str() != "type") {
+                    match value.as_str() {
+                        Some(s) if jinja => self.templates.push(s.to_string()),
+                        _ => self.collect(value, nested),
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+}
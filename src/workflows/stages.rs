@@ -1,11 +1,12 @@
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 
-use crate::error::Error;
-pub use crate::generated::get_runner;
+use crate::{error::Error, schema::WorkflowStageData};
 
-use super::Context;
+use super::{wrapper::StageWrapper, Context};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
 pub enum StageOutput {
     Text(String),
     List(Vec<String>),
@@ -17,6 +18,10 @@ pub trait StageRunner: Send + Sync {
     async fn run<'a>(&self, ctx: &Context<'a>) -> Result<StageOutput, Error>;
 }
 
+pub fn get_runner<'a>(stage: &'a WorkflowStageData) -> Box<dyn StageRunner + 'a> {
+    Box::new(StageWrapper::new(stage, crate::generated::get_runner(stage)))
+}
+
 pub mod user_input;
 pub mod ai_processing;
 pub mod ai_reshape;
@@ -36,3 +41,9 @@ pub mod warn;
 pub mod split;
 pub mod echo;
 pub mod try_catch;
+pub mod switch;
+pub mod parallel;
+pub mod call_workflow;
+pub mod map_reduce;
+pub mod fail;
+pub mod plugin;
@@ -3,8 +3,7 @@ use macros::stage;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::{error::Error, workflows::Context, llm::{Message, Response}, schema::Model};
-use crate::llm;
+use crate::{error::Error, workflows::{dry_run::DryRun, Context}, llm::{Message, Response}, schema::Model, workflows::template::TemplateEngine};
 
 use super::{StageRunner, StageOutput};
 
@@ -13,6 +12,8 @@ pub struct AiProcessingStageInfo {
     pub prompt: String,
     pub system_message: String,
     pub model: Model,
+    #[serde(default)]
+    pub engine: TemplateEngine,
 }
 
 #[stage(AiProcessingStageInfo)]
@@ -29,16 +30,20 @@ impl<'a> AiProcessingStageRunner<'a> {
 #[async_trait]
 impl<'a> StageRunner for AiProcessingStageRunner<'a> {
     async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
-        let result = llm::call_openai(
+        let system_message = ctx.render(&self.template.system_message, self.template.engine)?;
+        let prompt = ctx.render(&self.template.prompt, self.template.engine)?;
+        if let Some(dry_run) = ctx.dry_run {
+            let inputs = [("model", self.template.model.name()), ("system_message", &system_message), ("prompt", &prompt)];
+            return dry_run.output(ctx, &inputs, DryRun::placeholder(ctx)).await;
+        }
+        let result = ctx.call_llm(
             vec![
-                Message::system(ctx.interpolate(&self.template.system_message)?),
-                Message::user(ctx.interpolate(&self.template.prompt)?),
+                Message::system(system_message),
+                Message::user(prompt),
             ],
             self.template.model.name(),
         ).await?;
-        let text = match result {
-            Response { text } => text,
-        };
+        let Response { text } = result;
         Ok(StageOutput::Text(text))
     }
 }
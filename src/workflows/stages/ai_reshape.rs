@@ -3,19 +3,20 @@ use macros::stage;
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
 
-use crate::llm;
 use crate::schema::Model;
 use crate::{
     error::Error,
     llm::{Message, Response},
-    workflows::Context,
+    workflows::{dry_run::DryRun, template::TemplateEngine, Context},
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct AiReshapeStageInfo {
     pub target: AiReshapeTarget,
     pub data: String,
-    pub model: Model
+    pub model: Model,
+    #[serde(default)]
+    pub engine: TemplateEngine,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -48,19 +49,25 @@ impl<'a> StageRunner for AiReshapeStageRunner<'a> {
                     "b",
                 ]"#,
         };
-        let result = llm::call_openai(
+        let data = ctx.render(&self.template.data, self.template.engine)?;
+        if let Some(dry_run) = ctx.dry_run {
+            let placeholder = match DryRun::placeholder(ctx) {
+                StageOutput::Text(text) => StageOutput::List(vec![text]),
+                placeholder => placeholder,
+            };
+            return dry_run.output(ctx, &[("model", self.template.model.name()), ("data", &data)], placeholder).await;
+        }
+        let result = ctx.call_llm(
             vec![
                 Message::system("Your task is to transform the data provided by the user into a JSON. You only output JSON."),
-                Message::user(ctx.interpolate(&self.template.data)?),
+                Message::user(data),
                 Message::system(message),
                 Message::user("Output:"),
             ],
             self.template.model.name(),
         )
         .await?;
-        let text = match result {
-            Response { text } => text,
-        };
+        let Response { text } = result;
         let output = match self.template.target {
             AiReshapeTarget::List => StageOutput::List(text_to_json_list(text)?),
         };
@@ -81,6 +88,6 @@ fn text_to_json_list(text: String) -> Result<Vec<String>, Error> {
             }
             Ok(result)
         }
-        _ => return Err(Error::RuntimeError(format!("Expected a JSON array of strings, got {}", text))),
+        _ => Err(Error::RuntimeError(format!("Expected a JSON array of strings, got {}", text))),
     }
 }
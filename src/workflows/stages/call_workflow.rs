@@ -0,0 +1,157 @@
+use std::collections::{BTreeMap, HashMap};
+
+use async_trait::async_trait;
+use macros::stage;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+use crate::{error::Error, workflows::{self, Context}};
+use super::{StageRunner, StageOutput};
+
+pub const MAX_CALL_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct CallWorkflowStageInfo {
+    /// Name of the workflow to run
+    pub workflow: String,
+    /// Templates rendered in the calling scope. A template that is a single `${name}` passes lists as they are.
+    #[serde(default)]
+    pub inputs: BTreeMap<String, String>,
+}
+
+#[stage(CallWorkflowStageInfo)]
+pub struct CallWorkflowStageRunner<'a> {
+    template: &'a CallWorkflowStageInfo,
+}
+
+impl<'a> CallWorkflowStageRunner<'a> {
+    pub fn new(template: &'a CallWorkflowStageInfo) -> Self {
+        Self { template }
+    }
+
+    fn render_input(&self, ctx: &Context<'_>, value: &str) -> Result<StageOutput, Error> {
+        let re = Regex::new(r"^\$\{([^}]+)\}$").unwrap();
+        match re.captures(value) {
+            Some(caps) => ctx.get_variable(&caps[1]).cloned(),
+            None => Ok(StageOutput::Text(ctx.interpolate(value)?)),
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> StageRunner for CallWorkflowStageRunner<'a> {
+    async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
+        let workflow = ctx.workflows.workflows.iter()
+            .find(|workflow| workflow.name == self.template.workflow)
+            .ok_or_else(|| Error::InvalidWorkflow(format!("No workflow found with the name: {}", self.template.workflow)))?;
+        if ctx.depth >= MAX_CALL_DEPTH {
+            return Err(Error::RuntimeError(format!("Calling workflow {} exceeds the maximum call depth of {}", workflow.name, MAX_CALL_DEPTH)));
+        }
+
+        let mut inputs = HashMap::new();
+        for input in &workflow.inputs {
+            let value = match (self.template.inputs.get(&input.name), &input.default) {
+                (Some(value), _) => self.render_input(ctx, value)?,
+                (None, Some(default)) => StageOutput::Text(default.clone()),
+                (None, None) => return Err(Error::InvalidWorkflow(format!("Workflow {} needs input {}", workflow.name, input.name))),
+            };
+            inputs.insert(input.name.clone(), value);
+        }
+
+        let callee_ctx = Context {
+            depth: ctx.depth + 1,
+            ..ctx.derive(ctx.variables)
+        };
+        let (output, variables) = workflows::execute_workflow(workflow, &callee_ctx, inputs).await?;
+
+        let outputs = workflows::workflow_outputs(workflow, variables);
+        ctx.export_all(outputs.into_iter()
+            .map(|(name, value)| (format!("{}.{}", ctx.stage_name, name), value))
+            .collect());
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interface::cli::CliInterface;
+    use crate::llm::OpenAi;
+    use crate::schema::Workflows;
+    use crate::workflows::{cancel::Cancellation, Exports, RunSummary};
+
+    use super::*;
+
+    const WORKFLOWS: &str = r#"
+workflows:
+  - name: summarize
+    inputs: [{ name: topic }, { name: files }, { name: tone, default: dry }]
+    outputs: [summary]
+    stages:
+      - { name: draft, stage: { type: set, value: "${topic} in a ${tone} tone" } }
+      - { name: count, stage: { type: set, engine: jinja, value: "{{ files | join('+') }}" } }
+      - { name: summary, stage: { type: set, value: "${draft}: ${count}" } }
+  - name: forever
+    stages:
+      - { name: again, stage: { type: call_workflow, workflow: forever } }
+"#;
+
+    // Runs a call without the load-time checks, with the exports of the call
+    async fn call(workflow: &str, inputs: &[(&str, &str)], depth: usize) -> (Result<StageOutput, Error>, Vec<(String, StageOutput)>) {
+        let workflows: Workflows = serde_yaml::from_str(WORKFLOWS).unwrap();
+        let mut variables = HashMap::new();
+        variables.insert("files".to_string(), StageOutput::List(vec!["a.rs".to_string(), "b.rs".to_string()]));
+        let info = CallWorkflowStageInfo {
+            workflow: workflow.to_string(),
+            inputs: inputs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        };
+        let interface = CliInterface;
+        let exports = Exports::default();
+        let ctx = Context {
+            variables: &variables,
+            interface: &interface,
+            llm: &OpenAi,
+            workdir: std::path::Path::new("."),
+            exports: &exports,
+            default_timeout: None,
+            workflows: &workflows,
+            stage_name: "call",
+            stage_path: &[],
+            journal: None,
+            summary: &RunSummary::default(),
+            dry_run: None,
+            cancellation: &Cancellation::default(),
+            depth,
+        };
+        let output = CallWorkflowStageRunner::new(&info).run(&ctx).await;
+        (output, exports.take())
+    }
+
+    #[tokio::test]
+    async fn test_outputs() {
+        let (output, exports) = call("summarize", &[("topic", "Rust"), ("files", "${files}")], 0).await;
+        assert!(matches!(output, Ok(StageOutput::Text(text)) if text == "Rust in a dry tone: a.rs+b.rs"));
+        // Only the declared outputs are exported, prefixed with the name of the calling stage
+        assert_eq!(exports.len(), 1);
+        assert_eq!(exports[0].0, "call.summary");
+        assert!(matches!(&exports[0].1, StageOutput::Text(text) if text == "Rust in a dry tone: a.rs+b.rs"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_input() {
+        let (output, exports) = call("summarize", &[("files", "${files}")], 0).await;
+        assert!(matches!(output, Err(Error::InvalidWorkflow(message)) if message == "Workflow summarize needs input topic"));
+        assert!(exports.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_max_depth() {
+        let (output, _) = call("forever", &[], 0).await;
+        let error = output.unwrap_err();
+        assert!(matches!(error.root(), Error::RuntimeError(message) if message.contains("maximum call depth of 16")));
+        // Each level adds the calling stage to the path of the error
+        assert_eq!(error.stage_path().len(), MAX_CALL_DEPTH);
+
+        let (output, _) = call("forever", &[], MAX_CALL_DEPTH).await;
+        assert!(matches!(output, Err(Error::RuntimeError(_))));
+    }
+}
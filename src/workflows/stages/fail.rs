@@ -0,0 +1,34 @@
+use async_trait::async_trait;
+use macros::stage;
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+use crate::{error::Error, workflows::Context};
+use super::{StageRunner, StageOutput};
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct FailStageInfo {
+    pub message: String,
+    /// Exit code of the process when the error is not caught, 1 by default
+    pub exit_code: Option<u8>,
+}
+
+#[stage(FailStageInfo)]
+pub struct FailStageRunner<'a> {
+    template: &'a FailStageInfo,
+}
+
+impl<'a> FailStageRunner<'a> {
+    pub fn new(template: &'a FailStageInfo) -> Self {
+        Self { template }
+    }
+}
+
+#[async_trait]
+impl<'a> StageRunner for FailStageRunner<'a> {
+    async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
+        Err(Error::Failed {
+            message: ctx.interpolate(&self.template.message)?,
+            exit_code: self.template.exit_code,
+        })
+    }
+}
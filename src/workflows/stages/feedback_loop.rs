@@ -2,8 +2,7 @@ use async_trait::async_trait;
 use macros::stage;
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
-use crate::{error::Error, workflows::Context, llm::{Message, Response}, schema::Model};
-use crate::llm;
+use crate::{error::Error, workflows::{template::TemplateEngine, Context}, llm::Message, schema::Model};
 use super::{StageRunner, StageOutput};
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -11,6 +10,8 @@ pub struct FeedbackLoopStageInfo {
     pub initial_input: String,
     pub info_message: String,
     pub model: Model,
+    #[serde(default)]
+    pub engine: TemplateEngine,
 }
 
 #[stage(FeedbackLoopStageInfo)]
@@ -27,10 +28,10 @@ impl<'a> FeedbackLoopStageRunner<'a> {
 #[async_trait]
 impl<'a> StageRunner for FeedbackLoopStageRunner<'a> {
     async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
-        let mut current_input = ctx.interpolate(&self.template.initial_input)?;
+        let mut current_input = ctx.render(&self.template.initial_input, self.template.engine)?;
 
         loop {
-            let info_message = ctx.interpolate(&self.template.info_message)?;
+            let info_message = ctx.render(&self.template.info_message, self.template.engine)?;
             let message = format!(r#"
             {current_input}
             If you are satisfied, respond with 'ok'. Otherwise, describe what should be improved."#);
@@ -40,7 +41,13 @@ impl<'a> StageRunner for FeedbackLoopStageRunner<'a> {
                 break;
             }
 
-            let result = llm::call_openai(
+            if let Some(dry_run) = ctx.dry_run {
+                let inputs = [("model", self.template.model.name()), ("info_message", &info_message), ("current_state", &current_input), ("feedback", &feedback)];
+                current_input = dry_run.text(ctx, &inputs).await?;
+                continue;
+            }
+
+            let result = ctx.call_llm(
                 vec![
                     Message::system(info_message),
                     Message::system("This is the current state:"),
@@ -49,12 +56,10 @@ impl<'a> StageRunner for FeedbackLoopStageRunner<'a> {
                     Message::user(feedback),
                     Message::system("You only output improved state without any other text."),
                 ],
-                &self.template.model.name(),
+                self.template.model.name(),
             ).await?;
 
-            current_input = match result {
-                Response { text } => text,
-            };
+            current_input = result.text;
         }
 
         Ok(StageOutput::Text(current_input))
@@ -1,17 +1,87 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::Path};
 
 use async_trait::async_trait;
+use futures::StreamExt;
 use macros::stage;
+use regex::Regex;
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
-use crate::{error::Error, workflows::Context, schema::WorkflowStageData};
+use tokio::sync::Mutex;
+use crate::{error::Error, workflows::{self, graph, Context}, schema::{Export, WorkflowStageData}};
 use super::{StageRunner, StageOutput};
 
+const MAX_RANGE_ITEMS: i128 = 1_000_000;
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ForEachStageInfo {
     pub stages: Vec<WorkflowStageData>,
-    pub list: String,
+    #[serde(flatten)]
+    pub source: ForEachSource,
+    /// Holds the current item, `${loop.index}` (starting at 0), `${loop.first}` and `${loop.last}` describe its position
     pub variable: String,
+    /// Every exported variable becomes a list with one entry per iteration
+    #[serde(default)]
+    pub exports: Vec<Export>,
+    /// Maximum number of items processed at once, the output keeps the order of the list
+    pub parallelism: Option<usize>,
+    #[serde(default)]
+    pub on_error: ErrorPolicy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ForEachSource {
+    /// Name of a variable holding a list
+    List(String),
+    /// Files matching a pattern relative to the working directory, like `src/**/*.rs`, in alphabetical order.
+    /// Absolute and `../` patterns give paths written the same way.
+    Glob(String),
+    /// Integers from `start` up to, but not including, `end`, at most a million of them
+    Range(Range),
+    /// Non-empty lines of the interpolated text
+    Lines(String),
+    /// Items of a JSON array in the interpolated text, items that are not strings are passed as JSON
+    Json(JsonSource),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Range {
+    #[serde(default)]
+    pub start: i64,
+    pub end: i64,
+    #[serde(default = "Range::default_step")]
+    pub step: i64,
+}
+
+impl Range {
+    fn default_step() -> i64 {
+        1
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct JsonSource {
+    pub value: String,
+    /// Dot-separated path to the array inside the value, like `data.files`
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPolicy {
+    /// Stops the loop at the first failed item
+    #[default]
+    Fail,
+    /// Leaves failed items out of the output
+    Skip,
+    /// Processes every item and reports all failures at the end
+    Collect,
+}
+
+struct ItemResult {
+    index: usize,
+    output: String,
+    exports: Vec<String>,
 }
 
 #[stage(ForEachStageInfo)]
@@ -23,45 +93,313 @@ impl<'a> ForEachStageRunner<'a> {
     pub fn new(template: &'a ForEachStageInfo) -> Self {
         Self { template }
     }
+
+    fn items(&self, ctx: &Context<'_>) -> Result<Vec<String>, Error> {
+        match &self.template.source {
+            ForEachSource::List(list) => {
+                let list_name = ctx.interpolate(list)?;
+                match ctx.get_variable(&list_name)? {
+                    StageOutput::List(l) => Ok(l.clone()),
+                    _ => Err(Error::VariableTypeMismatch(format!("{} is not a list", list_name))),
+                }
+            },
+            ForEachSource::Glob(pattern) => glob(ctx.workdir, &ctx.interpolate(pattern)?),
+            ForEachSource::Range(range) => range_items(range),
+            ForEachSource::Lines(text) => Ok(ctx.interpolate(text)?.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(String::from)
+                .collect()),
+            ForEachSource::Json(source) => json_items(&ctx.interpolate(&source.value)?, source.path.as_deref()),
+        }
+    }
+
+    async fn run_item(&self, ctx: &Context<'_>, variable: &str, index: usize, count: usize, item: &str) -> Result<ItemResult, Error> {
+        let mut variables: HashMap<String, StageOutput> = (*ctx.variables).clone();
+        variables.insert(variable.to_string(), StageOutput::Text(item.to_string()));
+        variables.insert("loop.index".to_string(), StageOutput::Text(index.to_string()));
+        variables.insert("loop.first".to_string(), StageOutput::Text((index == 0).to_string()));
+        variables.insert("loop.last".to_string(), StageOutput::Text((index + 1 == count).to_string()));
+        let last_output = workflows::run_stages(&self.template.stages, ctx, &mut variables).await?;
+        log::debug!("Last output: {:?}", last_output);
+        let output = match last_output {
+            StageOutput::Text(s) => s,
+            _ => return Err(Error::VariableTypeMismatch(format!("{} is not a text", variable))),
+        };
+        let exports = ctx.resolve_exports(&self.template.exports, &variables)?
+            .into_iter()
+            .map(|(_, value)| match value {
+                StageOutput::Text(s) => s,
+                StageOutput::List(vec) => vec.join("\n"),
+                StageOutput::None => String::new(),
+            })
+            .collect();
+        Ok(ItemResult { index, output, exports })
+    }
 }
 
 #[async_trait]
 impl<'a> StageRunner for ForEachStageRunner<'a> {
     async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
         let variable = ctx.interpolate(&self.template.variable)?;
-        let mut outputs = Vec::new();
-        let list_name = ctx.interpolate(&self.template.list)?;
-        let list = match ctx.get_variable(&list_name)? {
-            StageOutput::List(l) => l,
-            _ => return Err(Error::VariableTypeMismatch(format!("{} is not a list", list_name))),
-        };
-        let mut counter = 0;
+        let list = self.items(ctx)?;
         let max = list.len();
-        for item in list {
-            log::info!("Loop {}/{}", counter, max);
-            let mut variables: HashMap<String, StageOutput> = (*ctx.variables).clone();
-            variables.insert(variable.clone(), StageOutput::Text(item.clone()));
-            let mut last_output = StageOutput::None;
-            for stage in &self.template.stages {
-                log::info!("Running stage in a loop: {}", stage.name);
-                let runner = super::get_runner(stage);
-                let output = {
-                    let loop_ctx = ctx.derive(&variables);
-                    runner.run(&loop_ctx).await?
+        let parallelism = self.template.parallelism.unwrap_or(1).max(1);
+        let variable = &variable;
+        // Items that interact with the user run one at a time, like interactive `parallel` branches
+        let interactive = self.template.stages.iter().any(|stage| graph::interactive(&stage.stage, ctx.workflows));
+        let interaction = &Mutex::new(());
+        let items: Vec<_> = list.iter().enumerate()
+            .map(|(index, item)| async move {
+                let _turn = match interactive {
+                    true => Some(interaction.lock().await),
+                    false => None,
                 };
-                log::info!("Stage {} finished", stage.name);
-                log::debug!("Stage {} output: {:?}", stage.name, output);
-                variables.insert(stage.name.clone(), output.clone());
-                last_output = output;
+                (index, self.run_item(ctx, variable, index, max, item).await)
+            })
+            .collect();
+        let mut results = futures::stream::iter(items).buffer_unordered(parallelism);
+
+        let mut finished = Vec::new();
+        let mut errors = Vec::new();
+        let mut done = 0;
+        while let Some((index, result)) = results.next().await {
+            done += 1;
+            log::info!("Loop {}/{} done", done, max);
+            match (result, self.template.on_error) {
+                (Ok(item), _) => finished.push(item),
+                // An interruption stops the loop whatever the policy
+                (Err(e), _) if e.is_interrupted() => return Err(e),
+                (Err(e), ErrorPolicy::Fail) => return Err(e),
+                (Err(e), ErrorPolicy::Skip) => log::warn!("Skipping failed item {} ({}): {}", index, list[index], e),
+                (Err(e), ErrorPolicy::Collect) => errors.push(e),
             }
-            log::debug!("Last output: {:?}", last_output);
-            outputs.push(match last_output {
-                StageOutput::Text(s) => s,
-                _ => return Err(Error::VariableTypeMismatch(format!("{} is not a text", variable))),
-            });
-            counter += 1;
+        }
+        match errors.len() {
+            0 => {},
+            1 => return Err(errors.remove(0)),
+            _ => return Err(Error::MultipleErrors(errors)),
+        }
+
+        finished.sort_by_key(|item| item.index);
+        let mut exported: Vec<Vec<String>> = vec![Vec::new(); self.template.exports.len()];
+        let mut outputs = Vec::new();
+        for item in finished {
+            for (values, value) in exported.iter_mut().zip(item.exports) {
+                values.push(value);
+            }
+            outputs.push(item.output);
+        }
+        for (export, values) in self.template.exports.iter().zip(exported) {
+            ctx.export(export.name(), StageOutput::List(values));
         }
 
         Ok(StageOutput::List(outputs))
     }
 }
+
+fn range_items(range: &Range) -> Result<Vec<String>, Error> {
+    if range.step == 0 {
+        return Err(Error::RuntimeError("Range step cannot be 0".to_string()));
+    }
+    // The items are all created before the loop starts, so the size is checked first
+    let (start, end, step) = (range.start as i128, range.end as i128, range.step as i128);
+    let count = ((end - start + step - step.signum()) / step).max(0);
+    if count > MAX_RANGE_ITEMS {
+        return Err(Error::RuntimeError(format!("Range from {} to {} has {} items, more than the maximum of {}", range.start, range.end, count, MAX_RANGE_ITEMS)));
+    }
+    let mut items = vec![];
+    let mut i = Some(range.start);
+    while let Some(value) = i.filter(|&i| (range.step > 0 && i < range.end) || (range.step < 0 && i > range.end)) {
+        items.push(value.to_string());
+        i = value.checked_add(range.step);
+    }
+    Ok(items)
+}
+
+// Matches paths relative to the working directory, `*` and `?` do not match `/`, `**/` matches any number of directories
+fn glob(workdir: &Path, pattern: &str) -> Result<Vec<String>, Error> {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            },
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    let re = Regex::new(&regex).map_err(|e| Error::RuntimeError(format!("Invalid pattern {}: {}", pattern, e)))?;
+
+    // Only the directory before the first wildcard has to be searched. Paths are written
+    // starting with that directory as in the pattern, so absolute and `../` patterns work too.
+    let base: Vec<&str> = pattern.split('/').take_while(|part| !part.contains(['*', '?'])).collect();
+    let base = base.join("/");
+    let mut paths: Vec<String> = walkdir::WalkDir::new(workdir.join(&base))
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            let relative = entry.path().strip_prefix(workdir.join(&base)).ok()?.to_string_lossy().to_string();
+            Some(match (base.as_str(), relative.as_str()) {
+                (base, "") => base.to_string(),
+                ("", relative) => relative.to_string(),
+                (base, relative) => format!("{}/{}", base.trim_end_matches('/'), relative),
+            })
+        })
+        .filter(|path| re.is_match(path))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn json_items(text: &str, path: Option<&str>) -> Result<Vec<String>, Error> {
+    let value: serde_json::Value = serde_json::from_str(text.trim()).map_err(|e| Error::RuntimeError(format!("Invalid JSON: {}", e)))?;
+    let mut array = &value;
+    for key in path.into_iter().flat_map(|path| path.split('.')) {
+        array = match array {
+            serde_json::Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            value => value.get(key),
+        }.ok_or_else(|| Error::RuntimeError(format!("JSON path {} not found", path.unwrap_or_default())))?;
+    }
+    match array {
+        serde_json::Value::Array(items) => Ok(items.iter()
+            .map(|item| match item {
+                serde_json::Value::String(s) => s.clone(),
+                item => item.to_string(),
+            })
+            .collect()),
+        _ => Err(Error::VariableTypeMismatch(format!("JSON at {} is not an array", path.unwrap_or("the top level")))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+
+    use crate::{engine::Engine, interface::Interface};
+
+    use super::*;
+
+    #[test]
+    fn test_json_items() {
+        let text = r#"{ "data": { "files": ["a.rs", { "name": "b.rs" }] } }"#;
+        assert_eq!(json_items(text, Some("data.files")).unwrap(), vec!["a.rs", r#"{"name":"b.rs"}"#]);
+        assert!(json_items(text, Some("data")).is_err());
+        assert!(json_items(text, Some("missing")).is_err());
+    }
+
+    #[test]
+    fn test_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in ["src/main.rs", "src/workflows/graph.rs", "src/workflows/notes.md", "build.rs"] {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        assert_eq!(glob(dir.path(), "src/**/*.rs").unwrap(), vec!["src/main.rs", "src/workflows/graph.rs"]);
+        assert_eq!(glob(dir.path(), "*.rs").unwrap(), vec!["build.rs"]);
+
+        let workdir = dir.path().join("src");
+        assert_eq!(glob(&workdir, "../*.rs").unwrap(), vec!["../build.rs"]);
+        let absolute = format!("{}/src/*/*.md", dir.path().display());
+        assert_eq!(glob(&workdir, &absolute).unwrap(), vec![format!("{}/src/workflows/notes.md", dir.path().display())]);
+    }
+
+    #[test]
+    fn test_range_items() {
+        let range = |start, end, step| range_items(&Range { start, end, step });
+        assert_eq!(range(0, 3, 1).unwrap(), vec!["0", "1", "2"]);
+        assert_eq!(range(5, 0, -2).unwrap(), vec!["5", "3", "1"]);
+        assert!(range(3, 0, 1).unwrap().is_empty());
+        assert_eq!(range(i64::MAX - 1, i64::MAX, 5).unwrap(), vec![(i64::MAX - 1).to_string()]);
+        assert_eq!(range(i64::MIN + 1, i64::MIN, -5).unwrap(), vec![(i64::MIN + 1).to_string()]);
+        assert!(range(0, 1, 0).is_err());
+        assert!(range(i64::MIN, i64::MAX, 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_error_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let workflow = |on_error: &str, failing: &str| format!(r#"
+  - name: {on_error}_{failing}
+    stages:
+      - name: loop
+        stage:
+          type: for_each
+          range: {{ end: 3 }}
+          variable: i
+          on_error: {on_error}
+          stages:
+            - {{ name: check, when: '${{i}} matches "^[{failing}]$"', stage: {{ type: fail, message: "item ${{i}}" }} }}
+            - {{ name: out, stage: {{ type: set, value: "${{i}}" }} }}
+"#);
+        let workflows = format!("workflows:{}{}{}", workflow("skip", "1"), workflow("collect", "1"), workflow("collect", "02"));
+        let engine = Engine::builder().workflows_str(&workflows).workdir(dir.path()).build().unwrap();
+        let no_inputs = || std::iter::empty::<(String, String)>();
+
+        let outputs = engine.run("skip_1", no_inputs()).await.unwrap();
+        assert_eq!(outputs.list("loop"), Some(&["0".to_string(), "2".to_string()][..]));
+        let error = engine.run("collect_1", no_inputs()).await.unwrap_err();
+        assert!(matches!(error.root(), Error::Failed { .. }), "{:?}", error);
+        let error = engine.run("collect_02", no_inputs()).await.unwrap_err();
+        assert!(matches!(error.root(), Error::MultipleErrors(errors) if errors.len() == 2), "{:?}", error);
+    }
+
+    static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+    static MOST_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+    // Answers after a while, counting how many questions are open at once
+    struct Slow;
+
+    #[async_trait]
+    impl Interface for Slow {
+        async fn send_message(&self, _msg: String) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn get_input(&self, msg: String) -> Result<String, Error> {
+            let active = ACTIVE.fetch_add(1, Ordering::SeqCst) + 1;
+            MOST_ACTIVE.fetch_max(active, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            ACTIVE.fetch_sub(1, Ordering::SeqCst);
+            Ok(format!("answer to {}", msg))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interactive_items() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::builder()
+            .workflows_str(r#"
+workflows:
+  - name: test
+    stages:
+      - name: loop
+        stage:
+          type: for_each
+          range: { end: 3 }
+          variable: i
+          parallelism: 3
+          stages:
+            - { name: ask, stage: { type: user_input, message: "item ${i}" } }
+"#)
+            .interface(Slow)
+            .workdir(dir.path())
+            .build()
+            .unwrap();
+        let outputs = engine.run("test", std::iter::empty::<(String, String)>()).await.unwrap();
+        let expected: Vec<String> = (0..3).map(|i| format!("answer to item {}", i)).collect();
+        assert_eq!(outputs.list("loop"), Some(&expected[..]));
+        assert_eq!(MOST_ACTIVE.load(Ordering::SeqCst), 1);
+    }
+}
@@ -4,15 +4,20 @@ use async_trait::async_trait;
 use macros::stage;
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
-use crate::{error::Error, workflows::Context, schema::WorkflowStageData};
+use crate::{error::Error, workflows::{self, Context, condition::Condition}, schema::{Export, WorkflowStageData}};
 use super::{StageRunner, StageOutput};
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct IfElseStageInfo {
-    pub left: String,
-    pub right: String,
+    pub condition: Option<Condition>,
+    /// Used when there is no condition, `if_stages` run when both values are equal after trimming
+    pub left: Option<String>,
+    pub right: Option<String>,
     pub if_stages: Vec<WorkflowStageData>,
     pub else_stages: Vec<WorkflowStageData>,
+    /// Variables that were not defined by the branch that ran are exported as empty
+    #[serde(default)]
+    pub exports: Vec<Export>,
 }
 
 #[stage(IfElseStageInfo)]
@@ -29,31 +34,26 @@ impl<'a> IfElseStageRunner<'a> {
 #[async_trait]
 impl<'a> StageRunner for IfElseStageRunner<'a> {
     async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
-        let left_value = ctx.interpolate(&self.template.left)?.trim().to_string();
-        let right_value = ctx.interpolate(&self.template.right)?.trim().to_string();
+        let matched = match (&self.template.condition, &self.template.left, &self.template.right) {
+            (Some(condition), _, _) => condition.evaluate(ctx)?,
+            (None, Some(left), Some(right)) => {
+                let left_value = ctx.interpolate(left)?.trim().to_string();
+                let right_value = ctx.interpolate(right)?.trim().to_string();
+                left_value == right_value
+            },
+            _ => return Err(Error::InvalidWorkflow("if_else needs a condition or left and right values".to_string())),
+        };
 
-        let stages = if left_value == right_value {
+        let stages = if matched {
             &self.template.if_stages
         } else {
             &self.template.else_stages
         };
 
         let mut variables: HashMap<String, StageOutput> = (*ctx.variables).clone();
-        let mut last_output = StageOutput::None;
-        
-        for stage in stages {
-            log::info!("Running stage in a condition: {}", stage.name);
-            let runner = super::get_runner(stage);
-            let output = {
-                let condition_ctx = ctx.derive(&variables);
-                runner.run(&condition_ctx).await?
-            };
-            log::info!("Stage {} finished", stage.name);
-            log::debug!("Stage {} output: {:?}", stage.name, output);
-            variables.insert(stage.name.clone(), output.clone());
-            last_output = output;
-        }
+        let last_output = workflows::run_stages(stages, ctx, &mut variables).await?;
         log::debug!("Last output: {:?}", last_output);
+        ctx.export_all(ctx.resolve_exports(&self.template.exports, &variables)?);
         Ok(last_output)
     }
 }
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use macros::stage;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, workflows::Context, llm::Message, schema::Model, workflows::template::TemplateEngine};
+
+use super::{StageRunner, StageOutput};
+
+const DEFAULT_TOKENS: usize = 2000;
+const MAX_REDUCE_LEVELS: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MapReduceStageInfo {
+    /// The text to process
+    pub input: String,
+    pub model: Model,
+    pub system_message: String,
+    #[serde(default)]
+    pub chunking: Chunking,
+    /// Runs on every chunk, with `${chunk}`, `${chunk.index}` (starting at 0) and `${chunk.count}`
+    pub map_prompt: String,
+    /// Combines map results, which are in `${results}` separated by blank lines.
+    /// When they do not fit `reduce_size`, groups of them are reduced first, and then the results of the groups.
+    pub reduce_prompt: String,
+    /// Maximum estimated tokens of the results combined in one reduce prompt, 2000 by default
+    pub reduce_size: Option<usize>,
+    /// Maximum number of prompts running at once
+    pub parallelism: Option<usize>,
+    #[serde(default)]
+    pub engine: TemplateEngine,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Chunking {
+    #[serde(default)]
+    pub unit: ChunkUnit,
+    pub size: usize,
+    /// Amount of the end of a chunk repeated at the start of the next one
+    #[serde(default)]
+    pub overlap: usize,
+}
+
+impl Default for Chunking {
+    fn default() -> Self {
+        Self { unit: ChunkUnit::Tokens, size: DEFAULT_TOKENS, overlap: 0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkUnit {
+    /// Estimated as 4 characters per token, chunks end at whitespace
+    #[default]
+    Tokens,
+    Lines,
+}
+
+#[stage(MapReduceStageInfo)]
+pub struct MapReduceStageRunner<'a> {
+    template: &'a MapReduceStageInfo,
+}
+
+impl<'a> MapReduceStageRunner<'a> {
+    pub fn new(template: &'a MapReduceStageInfo) -> Self {
+        Self { template }
+    }
+
+    async fn complete(&self, ctx: &Context<'_>, prompt: &str, values: Vec<(&str, String)>) -> Result<String, Error> {
+        let mut variables: HashMap<String, StageOutput> = (*ctx.variables).clone();
+        for (name, value) in values {
+            variables.insert(name.to_string(), StageOutput::Text(value));
+        }
+        let ctx = ctx.derive(&variables);
+        let system_message = ctx.render(&self.template.system_message, self.template.engine)?;
+        let prompt = ctx.render(prompt, self.template.engine)?;
+        if let Some(dry_run) = ctx.dry_run {
+            let inputs = [("model", self.template.model.name()), ("system_message", &system_message), ("prompt", &prompt)];
+            return dry_run.text(&ctx, &inputs).await;
+        }
+        let response = ctx.call_llm(
+            vec![
+                Message::system(system_message),
+                Message::user(prompt),
+            ],
+            self.template.model.name(),
+        ).await?;
+        Ok(response.text)
+    }
+
+    async fn map(&self, ctx: &Context<'_>, chunks: Vec<String>) -> Result<Vec<String>, Error> {
+        let count = chunks.len();
+        let prompts: Vec<_> = chunks.into_iter().enumerate()
+            .map(|(index, chunk)| self.complete(ctx, &self.template.map_prompt, vec![
+                ("chunk", chunk),
+                ("chunk.index", index.to_string()),
+                ("chunk.count", count.to_string()),
+            ]))
+            .collect();
+        let mut results = futures::stream::iter(prompts).buffered(self.parallelism());
+        let mut outputs = Vec::new();
+        while let Some(result) = results.next().await {
+            outputs.push(result?);
+            log::info!("Map {}/{} done", outputs.len(), count);
+        }
+        Ok(outputs)
+    }
+
+    async fn reduce(&self, ctx: &Context<'_>, mut results: Vec<String>) -> Result<String, Error> {
+        let reduce_size = self.template.reduce_size.unwrap_or(DEFAULT_TOKENS);
+        for _ in 0..MAX_REDUCE_LEVELS {
+            if estimate_tokens(&results.join("\n\n")) <= reduce_size || results.len() == 1 {
+                return self.complete(ctx, &self.template.reduce_prompt, vec![("results", results.join("\n\n"))]).await;
+            }
+            let groups = group(results, reduce_size);
+            // Every result is larger than the reduce size on its own, so grouping cannot shrink them anymore
+            if groups.iter().all(|group| group.len() == 1) {
+                log::warn!("Results do not fit reduce_size, reducing all {} of them at once", groups.len());
+                return self.complete(ctx, &self.template.reduce_prompt, vec![("results", groups.concat().join("\n\n"))]).await;
+            }
+            log::info!("Reducing {} groups of results", groups.len());
+            let prompts: Vec<_> = groups.into_iter()
+                .map(|group| self.complete(ctx, &self.template.reduce_prompt, vec![("results", group.join("\n\n"))]))
+                .collect();
+            results = futures::stream::iter(prompts).buffered(self.parallelism())
+                .collect::<Vec<_>>().await
+                .into_iter()
+                .collect::<Result<_, _>>()?;
+        }
+        Err(Error::MaxIterationsExceeded)
+    }
+
+    fn parallelism(&self) -> usize {
+        self.template.parallelism.unwrap_or(1).max(1)
+    }
+}
+
+#[async_trait]
+impl<'a> StageRunner for MapReduceStageRunner<'a> {
+    async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
+        let input = ctx.interpolate(&self.template.input)?;
+        let chunks = chunk(&input, &self.template.chunking);
+        log::info!("Split input into {} chunks", chunks.len());
+        if chunks.is_empty() {
+            return Ok(StageOutput::Text(String::new()));
+        }
+        let results = self.map(ctx, chunks).await?;
+        Ok(StageOutput::Text(self.reduce(ctx, results).await?))
+    }
+}
+
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+fn chunk(text: &str, chunking: &Chunking) -> Vec<String> {
+    let pieces: Vec<&str> = match chunking.unit {
+        ChunkUnit::Tokens => text.split_inclusive(char::is_whitespace).collect(),
+        ChunkUnit::Lines => text.split_inclusive('\n').collect(),
+    };
+    let weights: Vec<usize> = pieces.iter()
+        .map(|piece| match chunking.unit {
+            ChunkUnit::Tokens => estimate_tokens(piece.trim_end()),
+            ChunkUnit::Lines => 1,
+        })
+        .collect();
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < pieces.len() {
+        let mut end = start;
+        let mut size = 0;
+        while end < pieces.len() && (end == start || size + weights[end] <= chunking.size) {
+            size += weights[end];
+            end += 1;
+        }
+        chunks.push(pieces[start..end].concat());
+        if end == pieces.len() {
+            break;
+        }
+        // The next chunk starts with the end of this one, but always moves forward
+        let mut next = end;
+        let mut overlap = 0;
+        while next > start + 1 && overlap + weights[next - 1] <= chunking.overlap {
+            overlap += weights[next - 1];
+            next -= 1;
+        }
+        start = next;
+    }
+    chunks
+}
+
+// Groups consecutive results so that every group fits the reduce size, a result that is too large gets its own group
+fn group(results: Vec<String>, reduce_size: usize) -> Vec<Vec<String>> {
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    let mut size = 0;
+    for result in results {
+        let tokens = estimate_tokens(&result);
+        match groups.last_mut() {
+            Some(group) if size + tokens <= reduce_size => group.push(result),
+            _ => {
+                groups.push(vec![result]);
+                size = 0;
+            },
+        }
+        size += tokens;
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex};
+
+    use crate::{engine::Engine, llm::{LlmProvider, Response}};
+
+    use super::*;
+
+    // Answers map prompts with 2 tokens and reduce prompts with 3, counting the prompts running at once
+    #[derive(Default)]
+    struct Stub {
+        prompts: Mutex<Vec<String>>,
+        active: AtomicUsize,
+        most_active: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl LlmProvider for Arc<Stub> {
+        async fn complete(&self, messages: Vec<Message>, _model: &str) -> Result<Response, Error> {
+            let prompt = match messages.last() {
+                Some(Message::UserMessage(prompt)) => prompt.trim().to_string(),
+                message => panic!("Expected a user message, got {:?}", message),
+            };
+            let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_active.fetch_max(active, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            self.prompts.lock().unwrap().push(prompt.clone());
+            let text = match prompt.strip_prefix("reduce ") {
+                Some(results) => format!("{:-<12}", format!("r{}", results.split("\n\n").count())),
+                None => format!("{:-<8}", prompt),
+            };
+            Ok(Response { text })
+        }
+    }
+
+    async fn run(stub: &Arc<Stub>, text: &str) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::builder()
+            .workflows_str(r#"
+workflows:
+  - name: summarize
+    inputs: [{ name: text }]
+    stages:
+      - name: summary
+        stage:
+          type: map_reduce
+          input: "${text}"
+          model: gpt-4
+          system_message: "s"
+          chunking: { unit: lines, size: 1 }
+          map_prompt: "map ${chunk}"
+          reduce_prompt: "reduce ${results}"
+          reduce_size: 4
+          parallelism: 4
+"#)
+            .llm(stub.clone())
+            .workdir(dir.path())
+            .build()
+            .unwrap();
+        let outputs = engine.run("summarize", [("text", text)]).await.unwrap();
+        outputs.text("summary").unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let stub = Arc::new(Stub::default());
+        let output = run(&stub, "1\n2\n3\n4\n5\n6\n7\n8\n").await;
+        let prompts = stub.prompts.lock().unwrap().clone();
+        let (maps, reduces): (Vec<String>, Vec<String>) = prompts.into_iter().partition(|prompt| prompt.starts_with("map "));
+        assert_eq!(maps.len(), 8);
+        let most_active = stub.most_active.load(Ordering::SeqCst);
+        assert!(most_active > 1 && most_active <= 4, "{} prompts ran at once", most_active);
+
+        // Pairs of map results fit the reduce size, the 4 results of those do not fit it even on their own
+        assert_eq!(reduces.len(), 5);
+        assert!(reduces[..4].contains(&"reduce map 1---\n\nmap 2---".to_string()));
+        assert_eq!(reduces[4], format!("reduce {}", ["r2----------"; 4].join("\n\n")));
+        assert_eq!(output, "r4----------");
+    }
+
+    #[tokio::test]
+    async fn test_run_empty() {
+        let stub = Arc::new(Stub::default());
+        assert_eq!(run(&stub, "").await, "");
+        assert!(stub.prompts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_chunk_lines_with_overlap() {
+        let chunking = Chunking { unit: ChunkUnit::Lines, size: 3, overlap: 1 };
+        let chunks = chunk("1\n2\n3\n4\n5\n6\n", &chunking);
+        assert_eq!(chunks, vec!["1\n2\n3\n", "3\n4\n5\n", "5\n6\n"]);
+    }
+
+    #[test]
+    fn test_chunk_tokens() {
+        let chunking = Chunking { unit: ChunkUnit::Tokens, size: 2, overlap: 0 };
+        let chunks = chunk("abcd efgh ijkl", &chunking);
+        assert_eq!(chunks, vec!["abcd efgh ", "ijkl"]);
+        assert_eq!(chunks.concat(), "abcd efgh ijkl");
+    }
+
+    #[test]
+    fn test_group() {
+        let results = vec!["a".repeat(8), "b".repeat(8), "c".repeat(16), "d".repeat(4)];
+        let groups = group(results, 4);
+        assert_eq!(groups.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 1, 1]);
+    }
+
+    #[test]
+    fn test_group_oversized_results() {
+        let results = vec!["a".repeat(20), "b".repeat(20), "c".repeat(20)];
+        let groups = group(results, 4);
+        assert_eq!(groups.iter().map(Vec::len).collect::<Vec<_>>(), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn test_chunk_empty() {
+        assert!(chunk("", &Chunking::default()).is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use futures::future;
+use tokio::sync::Mutex;
+use macros::stage;
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+use crate::{error::Error, workflows::{self, graph, Context}, schema::{Export, WorkflowStageData}};
+use super::{StageRunner, StageOutput};
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ParallelStageInfo {
+    /// Branches run concurrently, each on its own copy of the variables.
+    /// The last output of every branch is stored under the branch name.
+    pub branches: Vec<ParallelBranch>,
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ParallelBranch {
+    pub name: String,
+    pub stages: Vec<WorkflowStageData>,
+    #[serde(default)]
+    pub exports: Vec<Export>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Cancels the other branches as soon as one fails
+    #[default]
+    FailFast,
+    /// Lets every branch finish and reports all failures
+    WaitAll,
+}
+
+#[stage(ParallelStageInfo)]
+pub struct ParallelStageRunner<'a> {
+    template: &'a ParallelStageInfo,
+}
+
+impl<'a> ParallelStageRunner<'a> {
+    pub fn new(template: &'a ParallelStageInfo) -> Self {
+        Self { template }
+    }
+}
+
+// Branches that interact with the user hold `interaction` while they run, so they run one at a time in order
+async fn run_branch(branch: &ParallelBranch, ctx: &Context<'_>, interaction: &Mutex<()>) -> Result<Vec<(String, StageOutput)>, Error> {
+    let _turn = match branch.stages.iter().any(|stage| graph::interactive(&stage.stage, ctx.workflows)) {
+        true => Some(interaction.lock().await),
+        false => None,
+    };
+    log::info!("Starting branch {}", branch.name);
+    let mut variables: HashMap<String, StageOutput> = (*ctx.variables).clone();
+    let output = workflows::run_stages(&branch.stages, ctx, &mut variables).await
+        .map_err(|e| e.at_stage(&branch.name))?;
+    log::info!("Branch {} finished", branch.name);
+    let mut results = vec![(branch.name.clone(), output)];
+    results.extend(ctx.resolve_exports(&branch.exports, &variables)?);
+    Ok(results)
+}
+
+#[async_trait]
+impl<'a> StageRunner for ParallelStageRunner<'a> {
+    async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
+        let interaction = Mutex::new(());
+        let branches = self.template.branches.iter().map(|branch| run_branch(branch, ctx, &interaction));
+        let results = match self.template.on_failure {
+            FailurePolicy::FailFast => future::try_join_all(branches).await?,
+            FailurePolicy::WaitAll => {
+                let (results, errors): (Vec<_>, Vec<_>) = future::join_all(branches).await
+                    .into_iter()
+                    .partition(|result| result.is_ok());
+                let mut errors: Vec<Error> = errors.into_iter().filter_map(Result::err).collect();
+                match errors.len() {
+                    0 => results.into_iter().filter_map(Result::ok).collect(),
+                    1 => return Err(errors.remove(0)),
+                    _ => return Err(Error::MultipleErrors(errors)),
+                }
+            },
+        };
+        for values in results {
+            ctx.export_all(values);
+        }
+        Ok(StageOutput::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+
+    use crate::{engine::Engine, interface::Interface};
+
+    use super::*;
+
+    static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+    static MOST_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+    // Answers after a while, counting how many questions are open at once
+    struct Slow;
+
+    #[async_trait]
+    impl Interface for Slow {
+        async fn send_message(&self, _msg: String) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn get_input(&self, msg: String) -> Result<String, Error> {
+            let active = ACTIVE.fetch_add(1, Ordering::SeqCst) + 1;
+            MOST_ACTIVE.fetch_max(active, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            ACTIVE.fetch_sub(1, Ordering::SeqCst);
+            Ok(format!("answer to {}", msg))
+        }
+    }
+
+    fn engine(dir: &std::path::Path) -> Engine {
+        Engine::builder()
+            .workflows_str(r#"
+workflows:
+  - name: exports
+    stages:
+      - name: gather
+        stage:
+          type: parallel
+          branches:
+            - name: first
+              stages:
+                - { name: a, stage: { type: set, value: "from first" } }
+              exports: [a]
+            - name: second
+              stages:
+                - { name: b, stage: { type: set, value: "from second" } }
+  - name: questions
+    stages:
+      - name: ask
+        stage:
+          type: parallel
+          branches:
+            - { name: first, stages: [{ name: q, stage: { type: user_input, message: "one" } }] }
+            - { name: second, stages: [{ name: q, stage: { type: user_input, message: "two" } }] }
+            - { name: third, stages: [{ name: q, stage: { type: user_input, message: "three" } }] }
+  - name: fail_fast
+    stages:
+      - name: both
+        stage:
+          type: parallel
+          branches: &branches
+            - { name: broken, stages: [{ name: fail, stage: { type: fail, message: "broken" } }] }
+            - name: slow
+              stages:
+                - { name: wait, stage: { type: shell_command, command: sleep, args: ["0.3"] } }
+                - { name: save, stage: { type: save_file, path: slow.txt, content: "done" } }
+            - { name: other, stages: [{ name: fail, stage: { type: fail, message: "other" } }] }
+  - name: wait_all
+    stages:
+      - name: both
+        stage:
+          type: parallel
+          on_failure: wait_all
+          branches: *branches
+"#)
+            .interface(Slow)
+            .workdir(dir)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_exports() {
+        let dir = tempfile::tempdir().unwrap();
+        let outputs = engine(dir.path()).run("exports", std::iter::empty::<(String, String)>()).await.unwrap();
+        assert_eq!(outputs.text("first"), Some("from first"));
+        assert_eq!(outputs.text("second"), Some("from second"));
+        assert_eq!(outputs.text("a"), Some("from first"));
+        assert_eq!(outputs.text("b"), None);
+    }
+
+    #[tokio::test]
+    async fn test_interactive_branches() {
+        let dir = tempfile::tempdir().unwrap();
+        let outputs = engine(dir.path()).run("questions", std::iter::empty::<(String, String)>()).await.unwrap();
+        assert_eq!(outputs.text("third"), Some("answer to three"));
+        assert_eq!(MOST_ACTIVE.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failure_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = engine(dir.path());
+        let error = engine.run("fail_fast", std::iter::empty::<(String, String)>()).await.unwrap_err();
+        assert!(matches!(error.root(), Error::Failed { .. }), "{:?}", error);
+        assert!(!dir.path().join("slow.txt").exists());
+
+        let error = engine.run("wait_all", std::iter::empty::<(String, String)>()).await.unwrap_err();
+        assert!(matches!(error.root(), Error::MultipleErrors(errors) if errors.len() == 2), "{:?}", error);
+        assert!(dir.path().join("slow.txt").exists());
+    }
+}
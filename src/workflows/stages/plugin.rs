@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::process::Stdio;
+
+use async_trait::async_trait;
+use macros::stage;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStdin, Command};
+
+use crate::{error::Error, workflows::{dry_run::DryRun, process, Context}};
+use super::{StageRunner, StageOutput};
+
+// Runs an external executable as a stage. The plugin gets one JSON line on stdin with the
+// stage config and variables, and answers with JSON lines on stdout until it sends its output:
+//
+//     -> {"type":"run","stage":"review","config":{...},"variables":{"diff":"..."}}
+//     <- {"type":"message","text":"Reviewing 3 files"}
+//     <- {"type":"ask","prompt":"Which file first?"}
+//     -> {"type":"answer","value":"main.rs"}
+//     <- {"type":"output","value":["ok","needs work"]}
+//
+// `{"type":"error","message":"..."}` fails the stage instead.
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PluginStageInfo {
+    /// Name of a plugin registered in `plugins`, or the path of an executable
+    pub plugin: String,
+    /// Sent to the plugin, `${}` in its strings is interpolated
+    #[serde(default)]
+    pub config: serde_json::Value,
+    /// Variables sent to the plugin, all variables of the scope when not set
+    pub variables: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request<'a> {
+    Run { stage: &'a str, config: &'a serde_json::Value, variables: HashMap<&'a str, &'a StageOutput> },
+    Answer { value: &'a str },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Reply {
+    Output {
+        #[serde(default)]
+        value: Option<StageOutput>,
+    },
+    Ask { prompt: String },
+    Message { text: String },
+    Error { message: String },
+}
+
+#[stage(PluginStageInfo)]
+pub struct PluginStageRunner<'a> {
+    template: &'a PluginStageInfo,
+}
+
+impl<'a> PluginStageRunner<'a> {
+    pub fn new(template: &'a PluginStageInfo) -> Self {
+        Self { template }
+    }
+
+    fn variables<'b>(&'b self, ctx: &'b Context<'_>) -> Result<HashMap<&'b str, &'b StageOutput>, Error> {
+        match &self.template.variables {
+            Some(names) => names.iter()
+                .map(|name| Ok((name.as_str(), ctx.get_variable(name)?)))
+                .collect(),
+            None => Ok(ctx.variables.iter().map(|(name, value)| (name.as_str(), value)).collect()),
+        }
+    }
+}
+
+fn interpolate_config(ctx: &Context<'_>, value: &serde_json::Value) -> Result<serde_json::Value, Error> {
+    Ok(match value {
+        serde_json::Value::String(s) => serde_json::Value::String(ctx.interpolate(s)?),
+        serde_json::Value::Array(values) => serde_json::Value::Array(values.iter()
+            .map(|value| interpolate_config(ctx, value))
+            .collect::<Result<_, _>>()?),
+        serde_json::Value::Object(values) => serde_json::Value::Object(values.iter()
+            .map(|(key, value)| Ok((key.clone(), interpolate_config(ctx, value)?)))
+            .collect::<Result<_, Error>>()?),
+        value => value.clone(),
+    })
+}
+
+async fn send(stdin: &mut ChildStdin, request: &Request<'_>) -> Result<(), Error> {
+    let mut line = serde_json::to_string(request).map_err(|e| Error::RuntimeError(e.to_string()))?;
+    line.push('\n');
+    stdin.write_all(line.as_bytes()).await.map_err(|e| Error::RuntimeError(format!("Cannot write to plugin: {}", e)))
+}
+
+#[async_trait]
+impl<'a> StageRunner for PluginStageRunner<'a> {
+    async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
+        let name = &self.template.plugin;
+        let config = interpolate_config(ctx, &self.template.config)?;
+        if let Some(dry_run) = ctx.dry_run {
+            let config = serde_json::to_string_pretty(&config).map_err(|e| Error::RuntimeError(e.to_string()))?;
+            return dry_run.output(ctx, &[("plugin", name.as_str()), ("config", config.as_str())], DryRun::placeholder(ctx)).await;
+        }
+        let variables = self.variables(ctx)?;
+
+        let mut command = match ctx.workflows.plugins.get(name) {
+            Some(plugin) => {
+                let mut command = Command::new(&plugin.command);
+                command.args(&plugin.args);
+                command
+            },
+            None => Command::new(name),
+        };
+        let (mut child, group) = process::spawn(command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .current_dir(ctx.workdir))
+            .map_err(|e| Error::RuntimeError(format!("Cannot start plugin {}: {}", name, e)))?;
+        let mut stdin = child.stdin.take().ok_or(Error::RuntimeError("Failed to open stdin".to_string()))?;
+        let stdout = child.stdout.take().ok_or(Error::RuntimeError("Failed to open stdout".to_string()))?;
+        let mut lines = BufReader::new(stdout).lines();
+
+        send(&mut stdin, &Request::Run { stage: ctx.stage_name, config: &config, variables }).await?;
+        let output = loop {
+            let line = lines.next_line().await.map_err(|e| Error::RuntimeError(e.to_string()))?;
+            let line = match line {
+                Some(line) if line.trim().is_empty() => continue,
+                Some(line) => line,
+                None => {
+                    let status = child.wait().await.map_err(|e| Error::RuntimeError(e.to_string()))?;
+                    return Err(Error::RuntimeError(format!("Plugin {} exited with {} before sending an output", name, status)));
+                },
+            };
+            let reply: Reply = serde_json::from_str(&line)
+                .map_err(|e| Error::RuntimeError(format!("Plugin {} sent an invalid message {}: {}", name, line, e)))?;
+            match reply {
+                Reply::Output { value } => break value.unwrap_or(StageOutput::None),
+                Reply::Ask { prompt } => {
+                    let answer = ctx.cancellation.guard(ctx.interface.get_input(prompt)).await?;
+                    send(&mut stdin, &Request::Answer { value: &answer }).await?;
+                },
+                Reply::Message { text } => ctx.interface.send_message(text).await?,
+                Reply::Error { message } => return Err(Error::RuntimeError(format!("Plugin {} failed: {}", name, message))),
+            }
+        };
+        drop(stdin);
+        let status = child.wait().await.map_err(|e| Error::RuntimeError(e.to_string()))?;
+        group.finished();
+        if !status.success() {
+            return Err(Error::RuntimeError(format!("Plugin {} exited with {}", name, status)));
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use crate::{engine::Engine, interface::Interface};
+
+    use super::*;
+
+    struct Answer;
+
+    #[async_trait]
+    impl Interface for Answer {
+        async fn send_message(&self, _msg: String) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn get_input(&self, msg: String) -> Result<String, Error> {
+            Ok(format!("answer to {}", msg))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugin() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("plugin.sh"), r#"
+read request
+case "$request" in
+  *'"stage":"greet"'*'"greeting":"Hello Ada"'*'"who":"Ada"'*) ;;
+  *) echo '{"type":"error","message":"unexpected request"}'; exit 0 ;;
+esac
+echo '{"type":"message","text":"thinking"}'
+echo '{"type":"ask","prompt":"Name?"}'
+read answer
+echo "{\"type\":\"output\",\"value\":[\"first\", $(echo "$answer" | sed 's/.*"value":\("[^"]*"\).*/\1/')]}"
+"#).unwrap();
+        let engine = Engine::builder()
+            .workflows_str(r#"
+plugins:
+  greeter: { command: sh, args: [plugin.sh] }
+workflows:
+  - name: greet
+    inputs: [{ name: who }]
+    stages:
+      - { name: greet, stage: { type: plugin, plugin: greeter, variables: [who], config: { greeting: "Hello ${who}" } } }
+  - name: broken
+    stages:
+      - { name: greet, stage: { type: plugin, plugin: greeter, config: { greeting: "Bye" } } }
+"#)
+            .interface(Answer)
+            .workdir(dir.path())
+            .build()
+            .unwrap();
+        let outputs = engine.run("greet", [("who", "Ada")]).await.unwrap();
+        assert_eq!(outputs.list("greet"), Some(&["first".to_string(), "answer to Name?".to_string()][..]));
+        let error = engine.run("broken", [("who", "Ada")]).await.unwrap_err();
+        assert_eq!(error.to_string(), "Error in stage greet: Runtime error: Plugin greeter failed: unexpected request");
+    }
+}
@@ -2,8 +2,9 @@ use async_trait::async_trait;
 use macros::stage;
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
-use std::process::{Command, Output};
-use crate::{error::Error, workflows::Context};
+use std::process::{Output, Stdio};
+use tokio::process::Command;
+use crate::{error::Error, workflows::{dry_run::DryRun, process, Context}};
 use super::{StageRunner, StageOutput};
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -27,12 +28,18 @@ impl<'a> StageRunner for PythonScriptStageRunner<'a> {
     async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
         let script = &self.template.script;        
         let script = ctx.interpolate(script)?;
-        let output: Output = Command::new("python")
+        if let Some(dry_run) = ctx.dry_run {
+            return dry_run.output(ctx, &[("script", &script)], DryRun::placeholder(ctx)).await;
+        }
+        let (child, group) = process::spawn(Command::new("python")
             .arg("-c")
             .arg(script)
             .current_dir(ctx.workdir)
-            .output()
-            .map_err(|e| Error::RuntimeError(e.to_string()))?;
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()))?;
+        let output: Output = child.wait_with_output().await.map_err(|e| Error::RuntimeError(e.to_string()))?;
+        group.finished();
 
         if !output.status.success() {
             return Err(Error::RuntimeError(format!("Script failed with error: {:?}", output.stderr)));
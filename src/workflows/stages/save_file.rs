@@ -29,6 +29,9 @@ impl<'a> StageRunner for SaveFileStageRunner<'a> {
     async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
         let path = ctx.interpolate(&self.template.path)?;
         let content = ctx.interpolate(&self.template.content)?;
+        if let Some(dry_run) = ctx.dry_run {
+            return dry_run.output(ctx, &[("path", &path), ("content", &content)], StageOutput::None).await;
+        }
         let workdir: &Path = ctx.workdir;
         let mut file = File::create(workdir.join(path)).map_err(|e| Error::RuntimeError(e.to_string()))?;
         file.write_all(content.as_bytes()).map_err(|e| Error::RuntimeError(e.to_string()))?;
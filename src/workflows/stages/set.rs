@@ -2,12 +2,14 @@ use async_trait::async_trait;
 use macros::stage;
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
-use crate::{error::Error, workflows::Context};
+use crate::{error::Error, workflows::Context, workflows::template::TemplateEngine};
 use super::{StageRunner, StageOutput};
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct SetStageInfo {
     pub value: String,
+    #[serde(default)]
+    pub engine: TemplateEngine,
 }
 
 #[stage(SetStageInfo)]
@@ -24,7 +26,7 @@ impl<'a> SetStageRunner<'a> {
 #[async_trait]
 impl<'a> StageRunner for SetStageRunner<'a> {
     async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
-        let parameter = ctx.interpolate(&self.template.value)?;
+        let parameter = ctx.render(&self.template.value, self.template.engine)?;
         Ok(StageOutput::Text(parameter))
     }
 }
@@ -2,9 +2,10 @@ use async_trait::async_trait;
 use macros::stage;
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
-use std::process::{Command, Stdio};
-use std::io::Write;
-use crate::{error::Error, workflows::Context};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use crate::{error::Error, workflows::{dry_run::DryRun, process, Context}};
 use super::{StageRunner, StageOutput};
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -34,22 +35,29 @@ impl<'a> StageRunner for ShellCommandStageRunner<'a> {
             .map(|arg| ctx.interpolate(arg))
             .collect();
         let args = args?;
+        if let Some(dry_run) = ctx.dry_run {
+            let stdin = self.template.stdin.as_ref().map(|input| ctx.interpolate(input)).transpose()?;
+            let mut inputs = vec![("command", command.as_str())];
+            inputs.extend(args.iter().map(|arg| ("arg", arg.as_str())));
+            inputs.extend(stdin.as_deref().map(|stdin| ("stdin", stdin)));
+            return dry_run.output(ctx, &inputs, DryRun::placeholder(ctx)).await;
+        }
         
-        let mut child = Command::new(&command)
+        let (mut child, group) = process::spawn(Command::new(&command)
             .args(&args)
             .current_dir(ctx.workdir)
             .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(|e| Error::RuntimeError(e.to_string()))?;
+            .stdout(Stdio::piped()))?;
 
         if let Some(input) = &self.template.stdin {
             let input = ctx.interpolate(input)?;
             let mut stdin = child.stdin.take().ok_or(Error::RuntimeError("Failed to open stdin".to_string()))?;
-            stdin.write_all(input.as_bytes()).map_err(|e| Error::RuntimeError(e.to_string()))?;
+            stdin.write_all(input.as_bytes()).await.map_err(|e| Error::RuntimeError(e.to_string()))?;
         }
+        drop(child.stdin.take());
 
-        let output = child.wait_with_output().map_err(|e| Error::RuntimeError(e.to_string()))?;
+        let output = child.wait_with_output().await.map_err(|e| Error::RuntimeError(e.to_string()))?;
+        group.finished();
         let stdout = String::from_utf8(output.stdout).map_err(|_| Error::RuntimeError("Failed to decode stdout".to_string()))?;
 
         Ok(StageOutput::Text(stdout))
@@ -2,9 +2,10 @@ use async_trait::async_trait;
 use macros::stage;
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
-use std::io::Write;
-use std::process::{Command, Stdio};
-use crate::{error::Error, workflows::Context};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use crate::{error::Error, workflows::{dry_run::DryRun, process, Context}};
 use super::{StageRunner, StageOutput};
 
 
@@ -35,20 +36,27 @@ impl<'a> StageRunner for ShellScriptStageRunner<'a> {
         };
 
         let script = ctx.interpolate(&self.template.script)?;
-        let mut command = Command::new(shell)
+        if let Some(dry_run) = ctx.dry_run {
+            let stdin = self.template.stdin.as_ref().map(|input| ctx.interpolate(input)).transpose()?;
+            let mut inputs = vec![("shell", shell), ("script", script.as_str())];
+            inputs.extend(stdin.as_deref().map(|stdin| ("stdin", stdin)));
+            return dry_run.output(ctx, &inputs, DryRun::placeholder(ctx)).await;
+        }
+        let (mut command, group) = process::spawn(Command::new(shell)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .arg("-c")
-            .arg(script)
-            .spawn().map_err(|e| Error::RuntimeError(e.to_string()))?;
+            .arg(script))?;
 
         if let Some(stdin_str) = &self.template.stdin {
             let stdin_str = ctx.interpolate(stdin_str)?;
             let stdin = command.stdin.as_mut().ok_or(Error::RuntimeError("Failed to open stdin".to_string()))?;
-            stdin.write_all(stdin_str.as_bytes()).map_err(|e| Error::RuntimeError(e.to_string()))?;
+            stdin.write_all(stdin_str.as_bytes()).await.map_err(|e| Error::RuntimeError(e.to_string()))?;
         }
+        drop(command.stdin.take());
 
-        let stdout = command.wait_with_output().map_err(|e| Error::RuntimeError(e.to_string()))?.stdout;
+        let stdout = command.wait_with_output().await.map_err(|e| Error::RuntimeError(e.to_string()))?.stdout;
+        group.finished();
         let stdout_str = String::from_utf8(stdout).map_err(|e| Error::RuntimeError(e.to_string()))?;
 
 
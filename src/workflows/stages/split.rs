@@ -40,7 +40,7 @@ impl<'a> StageRunner for SplitStageRunner<'a> {
         }
 
         if self.template.remove_empty {
-            parts = parts.into_iter().filter(|s| !s.is_empty()).collect();
+            parts.retain(|s| !s.is_empty());
         }
 
         Ok(StageOutput::List(parts))
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use macros::stage;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+use crate::{error::Error, workflows::{self, Context, condition::Condition}, schema::{Export, WorkflowStageData}};
+use super::{StageRunner, StageOutput};
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SwitchStageInfo {
+    pub value: String,
+    pub cases: Vec<SwitchCase>,
+    /// Runs when no case matches, the output is empty if there is no default
+    #[serde(default)]
+    pub default: Vec<WorkflowStageData>,
+    #[serde(default)]
+    pub exports: Vec<Export>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SwitchCase {
+    #[serde(flatten)]
+    pub matcher: CaseMatcher,
+    pub stages: Vec<WorkflowStageData>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CaseMatcher {
+    /// Matches when the value is equal to the interpolated text after trimming
+    Equals(String),
+    /// Matches when the value matches the regular expression
+    Matches(String),
+    Condition(Condition),
+}
+
+impl SwitchStageInfo {
+    // Regular expressions of the `matches` cases, checked when the workflow is loaded
+    pub fn patterns(&self) -> impl Iterator<Item = &str> {
+        self.cases.iter().filter_map(|case| match &case.matcher {
+            CaseMatcher::Matches(pattern) => Some(pattern.as_str()),
+            _ => None,
+        })
+    }
+}
+
+#[stage(SwitchStageInfo)]
+pub struct SwitchStageRunner<'a> {
+    template: &'a SwitchStageInfo,
+}
+
+impl<'a> SwitchStageRunner<'a> {
+    pub fn new(template: &'a SwitchStageInfo) -> Self {
+        Self { template }
+    }
+
+    fn find_stages(&self, ctx: &Context<'_>) -> Result<&'a [WorkflowStageData], Error> {
+        let value = ctx.interpolate(&self.template.value)?;
+        for (i, case) in self.template.cases.iter().enumerate() {
+            let matched = match &case.matcher {
+                CaseMatcher::Equals(expected) => value.trim() == ctx.interpolate(expected)?.trim(),
+                CaseMatcher::Matches(pattern) => {
+                    let re = Regex::new(&ctx.interpolate(pattern)?).map_err(|e| Error::RuntimeError(e.to_string()))?;
+                    re.is_match(&value)
+                },
+                CaseMatcher::Condition(condition) => condition.evaluate(ctx)?,
+            };
+            if matched {
+                log::info!("Switch case {} matched", i + 1);
+                return Ok(&case.stages);
+            }
+        }
+        log::info!("No switch case matched, running default stages");
+        Ok(&self.template.default)
+    }
+}
+
+#[async_trait]
+impl<'a> StageRunner for SwitchStageRunner<'a> {
+    async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
+        let stages = self.find_stages(ctx)?;
+        let mut variables: HashMap<String, StageOutput> = (*ctx.variables).clone();
+        let last_output = workflows::run_stages(stages, ctx, &mut variables).await?;
+        log::debug!("Last output: {:?}", last_output);
+        ctx.export_all(ctx.resolve_exports(&self.template.exports, &variables)?);
+        Ok(last_output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+
+    fn engine(dir: &std::path::Path) -> Engine {
+        Engine::builder()
+            .workflows_str(r#"
+workflows:
+  - name: route
+    inputs: [{ name: answer }]
+    stages:
+      - name: route
+        stage:
+          type: switch
+          value: "${answer}"
+          cases:
+            - equals: " yes "
+              stages:
+                - { name: result, stage: { type: set, value: "equals" } }
+            - matches: "^[0-9]+$"
+              stages:
+                - { name: result, stage: { type: set, value: "number" } }
+            - condition: '${answer} contains "maybe"'
+              stages:
+                - { name: result, stage: { type: set, value: "condition" } }
+            - matches: "^12"
+              stages:
+                - { name: result, stage: { type: set, value: "second match" } }
+          default:
+            - { name: result, stage: { type: set, value: "default" } }
+          exports: [result]
+"#)
+            .workdir(dir)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cases() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = engine(dir.path());
+        for (answer, expected) in [("yes", "equals"), ("123", "number"), ("well, maybe", "condition"), ("no", "default")] {
+            let outputs = engine.run("route", [("answer", answer)]).await.unwrap();
+            assert_eq!(outputs.text("route"), Some(expected), "answer {}", answer);
+            assert_eq!(outputs.text("result"), Some(expected), "answer {}", answer);
+        }
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let workflows = |pattern: &str| format!(r#"
+workflows:
+  - name: route
+    stages:
+      - name: route
+        stage:
+          type: switch
+          value: "a"
+          cases:
+            - {{ matches: "{}", stages: [] }}
+"#, pattern);
+        assert!(Engine::builder().workflows_str(workflows("^a+$")).build().is_ok());
+        assert!(Engine::builder().workflows_str(workflows("(a")).build().is_err());
+        assert!(Engine::builder().workflows_str(workflows("(${a}")).build().is_ok());
+    }
+}
@@ -3,12 +3,11 @@ use macros::stage;
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
 
-use crate::llm;
 use crate::schema::Model;
 use crate::{
     error::Error,
     llm::{Message, Response},
-    workflows::Context,
+    workflows::{template::TemplateEngine, Context},
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -16,6 +15,8 @@ pub struct ToJsonStageInfo {
     pub data: String,
     pub model: Model,
     pub example: String,
+    #[serde(default)]
+    pub engine: TemplateEngine,
 }
 
 use super::{StageOutput, StageRunner};
@@ -34,21 +35,25 @@ impl<'a> ToJsonStageRunner<'a> {
 #[async_trait]
 impl<'a> StageRunner for ToJsonStageRunner<'a> {
     async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
-        let result = llm::call_openai(
+        let data = ctx.render(&self.template.data, self.template.engine)?;
+        let example = ctx.render(&self.template.example, self.template.engine)?;
+        if let Some(dry_run) = ctx.dry_run {
+            let inputs = [("model", self.template.model.name()), ("data", &data), ("example", &example)];
+            return dry_run.output(ctx, &inputs, StageOutput::Text("{}".to_string())).await;
+        }
+        let result = ctx.call_llm(
             vec![
                 Message::system("Your task is to transform the data provided by the user into a JSON. You only output JSON."),
-                Message::user(ctx.interpolate(&self.template.data)?),
+                Message::user(data),
                 Message::system("Here's an example of what I want:"),
-                Message::user(ctx.interpolate(&self.template.example)?),
+                Message::user(example),
                 Message::user("Output:"),
             ],
             self.template.model.name(),
         )
         .await?;
         
-        let text = match result {
-            Response { text } => text,
-        };
+        let Response { text } = result;
         let json_output = text_to_json(text)?;
         Ok(StageOutput::Text(json_output))
     }
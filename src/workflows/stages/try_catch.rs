@@ -1,15 +1,50 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
 use macros::stage;
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
-use crate::{error::Error, workflows::Context, schema::WorkflowStageData};
+use crate::{error::{Error, ErrorKind}, workflows::{self, Context}, schema::{Export, WorkflowStageData}};
 use super::{StageRunner, StageOutput};
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct TryStageInfo {
     pub stages: Vec<WorkflowStageData>,
-    pub ok_result: String,
-    pub error_result: String,
+    /// Output when the stages succeed, defaults to the output of the last stage
+    pub ok_result: Option<String>,
+    /// Output for errors that no `catch` clause handles.
+    /// Without it such errors are raised again after `finally` has run.
+    pub error_result: Option<String>,
+    /// Handlers tried in order, the first one matching the error runs.
+    /// `${error.message}`, `${error.kind}` and `${error.stage}` describe the error.
+    #[serde(default)]
+    pub catch: Vec<CatchClause>,
+    /// Always run after the stages and the error handling, even when the error is raised again
+    #[serde(default)]
+    pub finally: Vec<WorkflowStageData>,
+    /// On failure, variables of stages that did not finish are exported as empty
+    #[serde(default)]
+    pub exports: Vec<Export>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct CatchClause {
+    /// Error kinds handled by this clause, all of them when empty
+    #[serde(default)]
+    pub kinds: Vec<ErrorKind>,
+    /// Names of the failing stages handled by this clause, all of them when empty
+    #[serde(default)]
+    pub failed_stages: Vec<String>,
+    /// The output of the last stage becomes the output of the try stage
+    pub stages: Vec<WorkflowStageData>,
+}
+
+impl CatchClause {
+    fn handles(&self, error: &Error) -> bool {
+        let stage = error.stage_path().last().copied();
+        (self.kinds.is_empty() || self.kinds.contains(&error.kind()))
+            && (self.failed_stages.is_empty() || stage.is_some_and(|stage| self.failed_stages.iter().any(|name| name == stage)))
+    }
 }
 
 #[stage(TryStageInfo)]
@@ -21,28 +56,165 @@ impl<'a> TryStageRunner<'a> {
     pub fn new(template: &'a TryStageInfo) -> Self {
         Self { template }
     }
+
+    async fn handle(&self, ctx: &Context<'_>, error: Error, variables: &mut HashMap<String, StageOutput>) -> Result<StageOutput, Error> {
+        if error.is_interrupted() {
+            return Err(error);
+        }
+        variables.insert("error.message".to_string(), StageOutput::Text(error.root().to_string()));
+        variables.insert("error.kind".to_string(), StageOutput::Text(error.kind().name().to_string()));
+        let stage = error.stage_path().last().map(|stage| stage.to_string()).unwrap_or_default();
+        variables.insert("error.stage".to_string(), StageOutput::Text(stage));
+
+        if let Some(clause) = self.template.catch.iter().find(|clause| clause.handles(&error)) {
+            log::warn!("Caught error: {}", error);
+            return workflows::run_stages(&clause.stages, ctx, variables).await;
+        }
+        match &self.template.error_result {
+            Some(error_result) => {
+                log::warn!("Caught error: {}", error);
+                Ok(StageOutput::Text(ctx.derive(variables).interpolate(error_result)?))
+            },
+            None => Err(error),
+        }
+    }
 }
 
 #[async_trait]
 impl<'a> StageRunner for TryStageRunner<'a> {
     async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
-        let mut variables: std::collections::HashMap<String, StageOutput> = (*ctx.variables).clone();
-
-        for stage in &self.template.stages {
-            let runner = super::get_runner(stage);
-            let new_ctx = ctx.derive(&variables);
-            match runner.run(&new_ctx).await {
-                Ok(output) => {
-                    variables.insert(stage.name.clone(), output.clone());
-                },
-                Err(_) =>  {
-                    let result = new_ctx.interpolate(&self.template.error_result)?;
-                    return Ok(StageOutput::Text(result));
+        let mut variables: HashMap<String, StageOutput> = (*ctx.variables).clone();
+
+        let result = match workflows::run_stages(&self.template.stages, ctx, &mut variables).await {
+            Ok(output) => match &self.template.ok_result {
+                Some(ok_result) => ctx.derive(&variables).interpolate(ok_result).map(StageOutput::Text),
+                None => Ok(output),
+            },
+            Err(e) => self.handle(ctx, e, &mut variables).await,
+        };
+        if !self.template.finally.is_empty() {
+            if let Err(e) = workflows::run_stages(&self.template.finally, ctx, &mut variables).await {
+                match &result {
+                    // The error of the stages is kept, as it is what the handling was about
+                    Err(original) if !e.is_interrupted() => log::warn!("Finally stages failed while raising {}: {}", original, e),
+                    _ => return Err(e),
                 }
             }
         }
+        let output = result?;
+        ctx.export_all(ctx.resolve_exports(&self.template.exports, &variables)?);
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{Engine, Outputs};
+
+    use super::*;
+
+    async fn run(stages: &str) -> Result<Outputs, Error> {
+        run_in(tempfile::tempdir().unwrap().path(), stages).await
+    }
+
+    async fn run_in(dir: &std::path::Path, stages: &str) -> Result<Outputs, Error> {
+        Engine::builder()
+            .workflows_str(format!("workflows:\n  - name: test\n    stages:\n{}", stages))
+            .workdir(dir)
+            .build()?
+            .run("test", std::iter::empty::<(String, String)>())
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_catch_missing_variable() {
+        let outputs = run(r#"
+      - name: guarded
+        stage:
+          type: try
+          stages:
+            - { name: use, stage: { type: set, value: "${missing}" } }
+          catch:
+            - kinds: [variable_not_found]
+              stages:
+                - { name: fallback, stage: { type: set, value: "caught ${error.kind}" } }
+"#).await.unwrap();
+        assert_eq!(outputs.text("guarded"), Some("caught variable_not_found"));
+    }
+
+    #[tokio::test]
+    async fn test_catch_kinds() {
+        let outputs = run(r#"
+      - name: guarded
+        stage:
+          type: try
+          stages:
+            - { name: broken, stage: { type: fail, message: "broken" } }
+          catch:
+            - kinds: [timeout]
+              stages:
+                - { name: timeout, stage: { type: set, value: "timeout" } }
+            - kinds: [failed]
+              failed_stages: [broken]
+              stages:
+                - { name: failed, stage: { type: set, value: "${error.kind} in ${error.stage}: ${error.message}" } }
+"#).await.unwrap();
+        assert_eq!(outputs.text("guarded"), Some("failed in broken: Failed: broken"));
+    }
+
+    #[tokio::test]
+    async fn test_uncaught_kind() {
+        let error = run(r#"
+      - name: guarded
+        stage:
+          type: try
+          stages:
+            - { name: broken, stage: { type: fail, message: "broken" } }
+          catch:
+            - kinds: [timeout, variable_not_found]
+              stages:
+                - { name: timeout, stage: { type: set, value: "timeout" } }
+"#).await.unwrap_err();
+        assert!(matches!(error.root(), Error::Failed { .. }), "{:?}", error);
+        assert_eq!(error.stage_path(), vec!["guarded", "broken"]);
+    }
+
+    #[tokio::test]
+    async fn test_finally() {
+        let dir = tempfile::tempdir().unwrap();
+        let guarded = |stage: &str| format!(r#"
+      - name: guarded
+        stage:
+          type: try
+          stages:
+            - {{ name: work, stage: {stage} }}
+          finally:
+            - {{ name: cleanup, stage: {{ type: save_file, path: cleanup.txt, content: "done" }} }}
+"#);
+        let outputs = run_in(dir.path(), &guarded(r#"{ type: set, value: "ok" }"#)).await.unwrap();
+        assert_eq!(outputs.text("guarded"), Some("ok"));
+        assert_eq!(std::fs::read_to_string(dir.path().join("cleanup.txt")).unwrap(), "done");
+
+        std::fs::remove_file(dir.path().join("cleanup.txt")).unwrap();
+        let error = run_in(dir.path(), &guarded(r#"{ type: fail, message: "broken" }"#)).await.unwrap_err();
+        assert!(matches!(error.root(), Error::Failed { .. }), "{:?}", error);
+        assert_eq!(std::fs::read_to_string(dir.path().join("cleanup.txt")).unwrap(), "done");
+    }
 
-        let result = ctx.derive(&variables).interpolate(&self.template.ok_result)?;
-        Ok(StageOutput::Text(result))
+    #[tokio::test]
+    async fn test_failing_finally() {
+        let guarded = |stage: &str| format!(r#"
+      - name: guarded
+        stage:
+          type: try
+          stages:
+            - {{ name: work, stage: {stage} }}
+          finally:
+            - {{ name: cleanup, stage: {{ type: fail, message: "cleanup" }} }}
+"#);
+        let error = run(&guarded(r#"{ type: fail, message: "work" }"#)).await.unwrap_err();
+        assert_eq!(error.stage_path(), vec!["guarded", "work"]);
+        let error = run(&guarded(r#"{ type: set, value: "ok" }"#)).await.unwrap_err();
+        assert_eq!(error.stage_path(), vec!["guarded", "cleanup"]);
     }
 }
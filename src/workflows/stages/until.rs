@@ -3,15 +3,21 @@ use async_trait::async_trait;
 use macros::stage;
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
-use crate::{error::Error, workflows::Context, schema::WorkflowStageData};
+use crate::{error::Error, workflows::{self, Context, condition::Condition}, schema::{Export, WorkflowStageData}};
 use super::{StageRunner, StageOutput};
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct UntilStageInfo {
     pub stages: Vec<WorkflowStageData>,
-    pub value: String,
-    pub expected_value: String,
+    /// The loop stops when the condition is true, the output is the last output of the stages
+    pub condition: Option<Condition>,
+    /// Used when there is no condition, the loop stops when both values are equal after trimming
+    pub value: Option<String>,
+    pub expected_value: Option<String>,
     pub max_iterations: Option<usize>,
+    /// Variables are exported with their values from the last iteration
+    #[serde(default)]
+    pub exports: Vec<Export>,
 }
 
 #[stage(UntilStageInfo)]
@@ -30,7 +36,7 @@ impl<'a> StageRunner for UntilStageRunner<'a> {
     async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
         let mut iterations = 0;
         let mut variables: HashMap<String, StageOutput> = (*ctx.variables).clone();
-        let mut last_value: String;
+        let output;
 
         loop {
             if let Some(max) = self.template.max_iterations {
@@ -39,29 +45,31 @@ impl<'a> StageRunner for UntilStageRunner<'a> {
                 }
             }
 
-            for stage in &self.template.stages {
-                log::info!("Running stage in the Until loop: {}", stage.name);
-                let runner = super::get_runner(stage);
-                let output = {
-                    let loop_ctx = ctx.derive(&variables);
-                    runner.run(&loop_ctx).await?
-                };
-                log::info!("Stage {} finished", stage.name);
-                log::debug!("Stage {} output: {:?}", stage.name, output);
-                variables.insert(stage.name.clone(), output);
-            }
+            let last_output = workflows::run_stages(&self.template.stages, ctx, &mut variables).await?;
 
             let loop_ctx = ctx.derive(&variables);
-            last_value = loop_ctx.interpolate(&self.template.value)?;
-            let expected_value = loop_ctx.interpolate(&self.template.expected_value)?;
-
-            if last_value.trim() == expected_value.trim() {
-                break;
+            match (&self.template.condition, &self.template.value, &self.template.expected_value) {
+                (Some(condition), _, _) => {
+                    if condition.evaluate(&loop_ctx)? {
+                        output = last_output;
+                        break;
+                    }
+                },
+                (None, Some(value), Some(expected_value)) => {
+                    let last_value = loop_ctx.interpolate(value)?;
+                    let expected_value = loop_ctx.interpolate(expected_value)?;
+                    if last_value.trim() == expected_value.trim() {
+                        output = StageOutput::Text(last_value);
+                        break;
+                    }
+                },
+                _ => return Err(Error::InvalidWorkflow("until needs a condition or value and expected_value".to_string())),
             }
 
             iterations += 1;
         }
 
-        Ok(StageOutput::Text(last_value))
+        ctx.export_all(ctx.resolve_exports(&self.template.exports, &variables)?);
+        Ok(output)
     }
 }
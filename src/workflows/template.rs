@@ -0,0 +1,133 @@
+use std::{collections::HashMap, path::Path, sync::{Arc, Mutex}};
+
+use minijinja::{AutoEscape, Environment, UndefinedBehavior, Value};
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+use super::stages::StageOutput;
+
+/// `interpolation` only replaces `${name}` references, `jinja` also supports
+/// `{% for %}`, `{% if %}` and `{% include %}` of files relative to the working directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateEngine {
+    #[default]
+    Interpolation,
+    Jinja,
+}
+
+// Variables are available by name and through the `vars` map,
+// which is needed for names like `user-stories` that are not valid identifiers.
+pub fn render_jinja(template: &str, variables: &HashMap<String, StageOutput>, workdir: &Path) -> Result<String, Error> {
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_auto_escape_callback(|_| AutoEscape::None);
+    env.set_keep_trailing_newline(true);
+    env.set_loader(minijinja::path_loader(workdir));
+    // Set to the name of a `${name}` reference that is not defined, to raise `VariableNotFound` for it
+    let missing = Arc::new(Mutex::new(None));
+    let missing_name = missing.clone();
+    env.add_filter("as_text", move |value: Value, name: String| {
+        if value.is_undefined() {
+            *missing_name.lock().unwrap() = Some(name.clone());
+            return Err(minijinja::Error::new(minijinja::ErrorKind::UndefinedError, format!("{} is not defined", name)));
+        }
+        as_text(value)
+    });
+
+    let vars: HashMap<&str, Value> = variables.iter()
+        .map(|(name, output)| (name.as_str(), to_value(output)))
+        .collect();
+    let mut context = vars.clone();
+    context.insert("vars", Value::from_serialize(&vars));
+
+    let source = convert_interpolations(template);
+    env.render_str(&source, context).map_err(|e| match missing.lock().unwrap().take() {
+        Some(name) => Error::VariableNotFound(name),
+        None => Error::TemplateError(e.to_string()),
+    })
+}
+
+// Rewrites `${name}` to a Jinja expression, except inside `{% raw %}` blocks, which are kept as written
+fn convert_interpolations(template: &str) -> String {
+    let raw = Regex::new(r"(?s)\{%-?\s*raw\s*-?%\}.*?\{%-?\s*endraw\s*-?%\}").unwrap();
+    let re = super::references::reference_regex();
+    let convert = |text: &str| re.replace_all(text, |caps: &regex::Captures| {
+        let name = caps[1].replace('\\', "\\\\").replace('"', "\\\"");
+        format!("{{{{ vars[\"{0}\"] | as_text(\"{0}\") }}}}", name)
+    }).to_string();
+    let mut result = String::new();
+    let mut end = 0;
+    for block in raw.find_iter(template) {
+        result.push_str(&convert(&template[end..block.start()]));
+        result.push_str(block.as_str());
+        end = block.end();
+    }
+    result.push_str(&convert(&template[end..]));
+    result
+}
+
+fn to_value(output: &StageOutput) -> Value {
+    match output {
+        StageOutput::Text(s) => Value::from(s.clone()),
+        StageOutput::List(vec) => Value::from(vec.clone()),
+        StageOutput::None => Value::from(()),
+    }
+}
+
+fn as_text(value: Value) -> Result<String, minijinja::Error> {
+    if value.is_none() {
+        return Ok(String::new());
+    }
+    if let Some(s) = value.as_str() {
+        return Ok(s.to_string());
+    }
+    let items = value.try_iter()?
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>();
+    Ok(items.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> HashMap<String, StageOutput> {
+        let mut variables = HashMap::new();
+        variables.insert("goal".to_string(), StageOutput::Text("Add a stage".to_string()));
+        variables.insert("relevant-files".to_string(), StageOutput::List(vec!["a.rs".to_string(), "b.rs".to_string()]));
+        variables.insert("empty".to_string(), StageOutput::None);
+        variables
+    }
+
+    #[test]
+    fn test_loops_and_conditions() {
+        let template = "{% for f in vars[\"relevant-files\"] %}{{ f }}\n===\n{% endfor %}{% if empty is none %}done{% endif %}";
+        let result = render_jinja(template, &variables(), Path::new(".")).unwrap();
+        assert_eq!(result, "a.rs\n===\nb.rs\n===\ndone");
+    }
+
+    #[test]
+    fn test_interpolation_compatibility() {
+        let result = render_jinja("${goal}: ${relevant-files}${empty}", &variables(), Path::new(".")).unwrap();
+        assert_eq!(result, "Add a stage: a.rs\nb.rs");
+    }
+
+    #[test]
+    fn test_raw_blocks() {
+        let template = "${goal} {% raw %}${goal} {{ goal }}{% endraw %} {%- raw -%} ${empty}{%- endraw %}";
+        let result = render_jinja(template, &variables(), Path::new(".")).unwrap();
+        assert_eq!(result, "Add a stage ${goal} {{ goal }}${empty}");
+    }
+
+    #[test]
+    fn test_undefined_variable() {
+        let result = render_jinja("{{ missing }}", &variables(), Path::new("."));
+        assert!(matches!(result, Err(Error::TemplateError(_))));
+        let result = render_jinja("Goal: ${goal}, ${missing}", &variables(), Path::new("."));
+        assert!(matches!(result, Err(Error::VariableNotFound(name)) if name == "missing"));
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+
+use crate::{error::Error, schema::{RetryPolicy, WorkflowStage, WorkflowStageData}};
+
+use super::{journal::Event, stages::{StageOutput, StageRunner}, Context, Exports};
+
+// Wraps the runner of every stage with the behaviour configured in `WorkflowStageData`
+pub struct StageWrapper<'a> {
+    stage: &'a WorkflowStageData,
+    runner: Box<dyn StageRunner + 'a>,
+}
+
+impl<'a> StageWrapper<'a> {
+    pub fn new(stage: &'a WorkflowStageData, runner: Box<dyn StageRunner + 'a>) -> Self {
+        Self { stage, runner }
+    }
+
+    async fn run_once(&self, ctx: &Context<'_>) -> Result<StageOutput, Error> {
+        // Stages made of other stages are limited by those, and waiting for the user should not time out
+        let exempt = !self.stage.stage.blocks().is_empty()
+            || self.stage.stage.waits_for_user()
+            || matches!(self.stage.stage, WorkflowStage::CallWorkflow(_));
+        let timeout = match self.stage.timeout {
+            Some(timeout) => Some(timeout.0),
+            None if exempt => None,
+            None => ctx.default_timeout,
+        };
+        match timeout {
+            // Dropping the future cancels the stage and kills its child processes
+            Some(timeout) => tokio::time::timeout(timeout, self.runner.run(ctx)).await
+                .map_err(|_| Error::Timeout(format!("Stage {} did not finish within {:?}", self.stage.name, timeout)))?,
+            None => self.runner.run(ctx).await,
+        }
+    }
+
+    async fn run_with_retry(&self, ctx: &Context<'_>, retry: &RetryPolicy) -> Result<StageOutput, Error> {
+        let mut attempt = 1;
+        loop {
+            // Exports of failed attempts are dropped
+            let exports = Exports::default();
+            let result = match self.run_once(&ctx.scoped(ctx.variables, &exports)).await {
+                Ok(output) => self.validate(ctx, retry, output),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(output) => {
+                    ctx.export_all(exports.take());
+                    return Ok(output);
+                },
+                Err(e) if attempt < retry.attempts && !e.is_interrupted() => {
+                    let delay = retry.backoff.delay(attempt - 1);
+                    log::warn!("Stage {} failed (attempt {}/{}), retrying in {:?}: {}", self.stage.name, attempt, retry.attempts, delay, e);
+                    ctx.cancellation.guard(async {
+                        tokio::time::sleep(delay).await;
+                        Ok(())
+                    }).await?;
+                    attempt += 1;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Sets the variables the stage would define to empty, so later stages can still use them
+    fn skip(&self, ctx: &Context<'_>) -> Result<StageOutput, Error> {
+        ctx.summary.skipped(&self.stage.name);
+        for name in self.stage.stage.exported_names() {
+            ctx.export(name, StageOutput::None);
+        }
+        if let WorkflowStage::CallWorkflow(info) = &self.stage.stage {
+            let outputs = ctx.workflows.workflows.iter()
+                .filter(|workflow| workflow.name == info.workflow)
+                .flat_map(|workflow| workflow.outputs.iter());
+            for output in outputs {
+                ctx.export(format!("{}.{}", self.stage.name, output), StageOutput::None);
+            }
+        }
+        match &self.stage.default {
+            Some(default) => Ok(StageOutput::Text(ctx.interpolate(default)?)),
+            None => Ok(StageOutput::None),
+        }
+    }
+
+    fn validate(&self, ctx: &Context<'_>, retry: &RetryPolicy, output: StageOutput) -> Result<StageOutput, Error> {
+        let condition = match &retry.validate {
+            Some(condition) => condition,
+            None => return Ok(output),
+        };
+        let mut variables: HashMap<String, StageOutput> = (*ctx.variables).clone();
+        variables.insert(self.stage.name.clone(), output.clone());
+        if condition.evaluate(&ctx.derive(&variables))? {
+            Ok(output)
+        } else {
+            Err(Error::ValidationFailed(format!("Output of stage {} does not satisfy `{}`", self.stage.name, condition.source())))
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> StageRunner for StageWrapper<'a> {
+    async fn run<'b>(&self, ctx: &Context<'b>) -> Result<StageOutput, Error> {
+        if let Some(condition) = &self.stage.when {
+            if !condition.evaluate(ctx).map_err(|e| e.at_stage(&self.stage.name))? {
+                log::info!("Skipping stage {}, `{}` is false", self.stage.name, condition.source());
+                ctx.record(Event::StageSkipped { path: ctx.stage_path, condition: condition.source() });
+                return self.skip(ctx).map_err(|e| e.at_stage(&self.stage.name));
+            }
+        }
+        let run = async {
+            match &self.stage.retry {
+                Some(retry) => self.run_with_retry(ctx, retry).await,
+                None => self.run_once(ctx).await,
+            }
+        };
+        // Block stages stop when the stages inside them are interrupted, so the error keeps its full path
+        let result = match self.stage.stage.blocks().is_empty() {
+            true => ctx.cancellation.guard(run).await,
+            false if ctx.cancellation.is_cancelled() => Err(Error::Interrupted),
+            false => run.await,
+        };
+        if result.is_ok() {
+            log::info!("Stage {} finished", self.stage.name);
+            ctx.summary.finished();
+        }
+        result.map_err(|e| e.at_stage(&self.stage.name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{engine::Engine, workflows::RunOptions};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_default_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::builder()
+            .workflows_str(r#"
+workflows:
+  - name: direct
+    timeout: 100ms
+    stages:
+      - { name: wait, stage: { type: shell_command, command: sleep, args: ["0.5"] } }
+  - name: called
+    timeout: 100ms
+    stages:
+      - { name: call, stage: { type: call_workflow, workflow: callee, inputs: {} } }
+  - name: callee
+    stages:
+      - { name: wait, stage: { type: shell_command, command: sleep, args: ["0.5"] } }
+"#)
+            .workdir(dir.path())
+            .build()
+            .unwrap();
+        let no_inputs = || std::iter::empty::<(String, String)>();
+        assert!(matches!(engine.run("direct", no_inputs()).await.unwrap_err().root(), Error::Timeout(_)));
+        assert!(engine.run("called", no_inputs()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        // Every attempt adds a line to a file and fails before the third one
+        let attempt = |file: &str| format!(r#"
+          type: try
+          stages:
+            - {{ name: count, stage: {{ type: shell_command, command: sh, args: ["-c", "echo x >> {file}; wc -l < {file}"] }} }}
+            - {{ name: early, when: '${{count}} < 3', stage: {{ type: fail, message: "attempt ${{count}}" }} }}
+            - {{ name: done, stage: {{ type: set, value: "attempt ${{count}}" }} }}"#);
+        let engine = Engine::builder()
+            .workflows_str(format!(r#"
+workflows:
+  - name: enough
+    stages:
+      - name: flaky
+        retry: {{ attempts: 3, backoff: {{ delay: 1ms }} }}
+        stage: {enough}
+  - name: too-few
+    stages:
+      - name: flaky
+        retry: {{ attempts: 2, backoff: {{ delay: 1ms }} }}
+        stage: {too_few}
+  - name: invalid
+    stages:
+      - name: flaky
+        retry: {{ attempts: 4, backoff: {{ delay: 1ms }}, validate: '${{flaky}} == "attempt 5"' }}
+        stage: {invalid}
+"#, enough = attempt("enough"), too_few = attempt("too-few"), invalid = attempt("invalid")))
+            .workdir(dir.path())
+            .build()
+            .unwrap();
+        let no_inputs = || std::iter::empty::<(String, String)>();
+        let attempts = |file: &str| std::fs::read_to_string(dir.path().join(file)).unwrap().lines().count();
+
+        let outputs = engine.run("enough", no_inputs()).await.unwrap();
+        assert_eq!(outputs.text("flaky").map(str::trim), Some("attempt 3"));
+        assert_eq!(attempts("enough"), 3);
+
+        let error = engine.run("too-few", no_inputs()).await.unwrap_err();
+        assert!(matches!(error.root(), Error::Failed { .. }), "{:?}", error);
+        assert_eq!(attempts("too-few"), 2);
+
+        // The stage succeeds from the third attempt on, but its output never satisfies `validate`
+        let error = engine.run("invalid", no_inputs()).await.unwrap_err();
+        assert!(matches!(error.root(), Error::ValidationFailed(message) if message.contains("stage flaky")));
+        assert_eq!(attempts("invalid"), 4);
+    }
+
+    #[tokio::test]
+    async fn test_skip() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::builder()
+            .workflows_str(r#"
+workflows:
+  - name: test
+    inputs: [{ name: mode }]
+    stages:
+      - name: review
+        when: ${mode} == "full"
+        default: "no review for ${mode}"
+        stage:
+          type: try
+          stages:
+            - { name: notes, stage: { type: set, value: "notes" } }
+          exports: [notes]
+      - name: call
+        when: ${mode} == "full"
+        stage: { type: call_workflow, workflow: callee }
+      - { name: report, stage: { type: set, value: "${review}|${notes}|${call.summary}" } }
+  - name: callee
+    outputs: [summary]
+    stages:
+      - { name: summary, stage: { type: set, value: "summary" } }
+"#)
+            .workdir(dir.path())
+            .build()
+            .unwrap();
+        let options = RunOptions {
+            variables: [("mode".to_string(), StageOutput::Text("quick".to_string()))].into(),
+            journal: true,
+            ..RunOptions::default()
+        };
+        let outputs = engine.run_with(Some("test"), &options).await.unwrap();
+        // The default is the output of the skipped stage, the names it would export stay empty
+        assert_eq!(outputs.text("review"), Some("no review for quick"));
+        assert!(matches!(outputs.get("notes"), Some(StageOutput::None)));
+        assert!(matches!(outputs.get("call"), Some(StageOutput::None)));
+        assert!(matches!(outputs.get("call.summary"), Some(StageOutput::None)));
+        assert_eq!(outputs.text("report"), Some("no review for quick||"));
+
+        let journals: Vec<_> = std::fs::read_dir(dir.path().join(".yamlchain/journals")).unwrap().collect();
+        assert_eq!(journals.len(), 1);
+        let content = std::fs::read_to_string(journals[0].as_ref().unwrap().path()).unwrap();
+        let events: Vec<serde_json::Value> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let skipped: Vec<&serde_json::Value> = events.iter()
+            .filter(|event| event["event"] == "stage_skipped")
+            .map(|event| &event["path"])
+            .collect();
+        assert_eq!(skipped, vec![&serde_json::json!(["review"]), &serde_json::json!(["call"])]);
+        let finished = events.iter().find(|event| event["event"] == "run_finished").unwrap();
+        assert_eq!(finished["summary"], "1 stages finished, 2 skipped (review, call)");
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_backoff() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::builder()
+            .workflows_str(r#"
+workflows:
+  - name: test
+    stages:
+      - name: block
+        retry: { attempts: 2, backoff: { delay: 1m } }
+        stage:
+          type: try
+          stages:
+            - { name: broken, stage: { type: fail, message: "broken" } }
+"#)
+            .workdir(dir.path())
+            .build()
+            .unwrap();
+        let options = RunOptions::default();
+        let started = Instant::now();
+        let (result, _) = tokio::join!(engine.run_with(Some("test"), &options), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            options.cancellation.cancel();
+        });
+        assert!(result.unwrap_err().is_interrupted());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}